@@ -1,12 +1,38 @@
-use super::model::*;
-use std::process::Command;
+use super::{connection, model::*, window_picker};
+use lazy_static::lazy_static;
 
-fn ceramic_do(cmd: &str) {
-    Command::new("sh")
-            .arg("-c")
-            .arg(format!("xprop -root -f CERAMIC_COMMAND 8u -set CERAMIC_COMMAND '{}'", cmd).as_str())
-            .output()
-            .expect("failed to execute process");
+// Ceramic watches the CERAMIC_COMMAND property on the root window
+lazy_static! {
+    static ref CERAMIC_COMMAND: xcb::Atom = connection::intern_atom("CERAMIC_COMMAND");
+    static ref UTF8_STRING: xcb::Atom = connection::intern_atom("UTF8_STRING");
+}
+
+pub fn ceramic_do(cmd: &str) {
+    let cmd = match window_picker::expand_placeholders(cmd) {
+        Some(cmd) => cmd,
+        None => return,
+    };
+
+    log::debug!("Ceramic command: {}", cmd);
+    let connection = connection::connection();
+    let root = connection.get_setup().roots().nth(0).unwrap().root();
+    if let Err(e) = xcb::change_property_checked(
+        connection,
+        xcb::PROP_MODE_REPLACE as u8,
+        root,
+        *CERAMIC_COMMAND,
+        *UTF8_STRING,
+        8,
+        cmd.as_bytes(),
+    )
+    .request_check()
+    {
+        log::error!(
+            "Failed to send ceramic command '{}': X error {}",
+            cmd,
+            e.error_code()
+        );
+    }
 }
 
 pub fn extend_model(model: &mut Model) {
@@ -20,7 +46,8 @@ pub fn extend_model(model: &mut Model) {
             Cmd + Opt + minus => { "Decrease Count" ceramic_do("layout/decrease_count") }
             Cmd + Opt + plus => { "Increase Count" ceramic_do("layout/increase_count") }
             Cmd + t => { "Tile Window" ceramic_do("tile_window: {focused_window}") }
-            Cmd + r => { "Launch" => window_manager::launch }
+            Cmd + r => { "Launch" launch }
+            Cmd + l => { "Adjust Layout …" "Change the ratio and count of the layout with repeated keys, until Return or a pause" => layout }
             group "Focus" {
                 Cmd + Tab => { "Next" ceramic_do("focus_on_next_window") }
                 Cmd + Shift + Tab => { "Previous" ceramic_do("focus_on_previous_window") }
@@ -35,7 +62,7 @@ pub fn extend_model(model: &mut Model) {
                 Cmd + 7 => { "Space 7" ceramic_do("switch_to_workspace_named: 7") }
                 Cmd + 8 => { "Space 8" ceramic_do("switch_to_workspace_named: 8") }
                 Cmd + 9 => { "Space 9" ceramic_do("switch_to_workspace_named: 9") }
-                Cmd + 0 => { "Select …" ceramic_do("focus_on_window: {selected_window}") }
+                Cmd + 0 => { "Select …" "Focus a window by typing the label shown over it" ceramic_do("focus_on_window: {selected_window}") }
             }
             group "Move" {
                 Cmd + Shift + j => { "Forward" ceramic_do("move_focused_window_forward") }
@@ -49,10 +76,19 @@ pub fn extend_model(model: &mut Model) {
                 Cmd + Shift + 7 => { "To Space 7" ceramic_do("move_focused_window_to_workspace_named: 7") }
                 Cmd + Shift + 8 => { "To Space 8" ceramic_do("move_focused_window_to_workspace_named: 8") }
                 Cmd + Shift + 9 => { "To Space 9" ceramic_do("move_focused_window_to_workspace_named: 9") }
-                Cmd + Shift + 0 => { "To …" ceramic_do("move_focused_window_to_position_of: {selected_window}") }
-                Cmd + Shift + Opt + 0 => { "Swap With …" ceramic_do("swap_focused_window_with: {selected_window}") }
-                Cmd + Opt + 0 => { "Pull To Head …" ceramic_do("move_focused_window_to_head") }
+                Cmd + Shift + 0 => { "To …" "Move the focused window to where a window picked by its label is" ceramic_do("move_focused_window_to_position_of: {selected_window}") }
+                Cmd + Shift + Opt + 0 => { "Swap With …" "Swap the focused window with a window picked by its label" ceramic_do("swap_focused_window_with: {selected_window}") }
+                Cmd + Opt + 0 => { "Pull To Head …" "Move the focused window to the head of the layout, where the main window goes" ceramic_do("move_focused_window_to_head") }
         }
      }
+        mode layout {
+            timeout 3
+            minus => { "Decrease Ratio" hydra ceramic_do("layout/decrease_ratio") }
+            plus => { "Increase Ratio" hydra ceramic_do("layout/increase_ratio") }
+            Opt + minus => { "Decrease Count" hydra ceramic_do("layout/decrease_count") }
+            Opt + plus => { "Increase Count" hydra ceramic_do("layout/increase_count") }
+            space => { "Cycle Layout" hydra ceramic_do("switch_to_next_layout") }
+            Return => { "Done" cancel }
+        }
     })
 }
@@ -0,0 +1,574 @@
+use super::{ceramic, ewmh, help, keystroke::KeySpec, model::*, process, window_picker};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fmt::{Display, Formatter},
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// The configuration file mirrors the structure of the bindings! macro:
+//
+//   ignored_modifiers = ["CapsLock", "NumLock"]
+//
+//   [help]
+//   anchor = "right"
+//   follow = "pointer"
+//   delay = 0.5
+//
+//   [help.theme]
+//   font = "Noto Sans 12px"
+//   background = "#fffff2"
+//
+//   [[global.binding]]
+//   key = "Escape"
+//   label = "Cancel Operation"
+//   cancel = true
+//
+//   [root]
+//   guard = { class = "Emacs" }
+//
+//   [[root.group]]
+//   name = "Focus"
+//   [[root.group.binding]]
+//   key = "Cmd + j"
+//   label = "Next"
+//   description = "Focus the next window in the layout"
+//   ceramic = "focus_on_next_window"
+//
+//   [[mode.applications.binding]]
+//   key = "t"
+//   label = "Terminal"
+//   exec = "rxvt"
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Error::Io(e) => write!(formatter, "{}", e),
+            Error::Parse(e) => write!(formatter, "{}", e),
+            Error::Invalid(message) => write!(formatter, "{}", message),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::Parse(e)
+    }
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("commando").join("config.toml"))
+}
+
+pub fn load(path: &Path) -> Result<Config, Error> {
+    log::debug!("Load configuration from {}", path.display());
+    Ok(toml::from_str(&fs::read_to_string(path)?)?)
+}
+
+// Longer than anything is worth waiting for, but short enough to add to an Instant
+const MAX_SECONDS: f64 = 24.0 * 60.0 * 60.0;
+
+// Seconds from the file as a duration, unless they're negative, not a number or
+// longer than a day
+pub fn duration(seconds: f64) -> Option<Duration> {
+    if seconds.is_finite() && seconds >= 0.0 && seconds <= MAX_SECONDS {
+        Some(Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    ignored_modifiers: Option<Vec<String>>,
+    help: Option<help::Settings>,
+    global: Option<Section>,
+    root: Option<Section>,
+    #[serde(default)]
+    mode: BTreeMap<String, Section>,
+}
+
+impl Config {
+    pub fn add_bindings(&self, model: &mut Model) -> Result<(), Error> {
+        if let Some(names) = &self.ignored_modifiers {
+            model.set_ignored_modifiers(names);
+        }
+        if let Some(settings) = &self.help {
+            settings.validate().map_err(Error::Invalid)?;
+            model.set_help_settings(settings.clone());
+        }
+
+        if let Some(section) = &self.global {
+            section.add_bindings(model, "@global")?;
+        }
+        if let Some(section) = &self.root {
+            section.add_bindings(model, "@root")?;
+        }
+        for (name, section) in &self.mode {
+            section.add_bindings(model, intern(name))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Section {
+    guard: Option<Guard>,
+    timeout: Option<f64>,    // seconds
+    help_delay: Option<f64>, // seconds
+    show_help: Option<bool>,
+    #[serde(default)]
+    binding: Vec<BindingSpec>,
+    #[serde(default)]
+    group: Vec<Group>,
+}
+
+impl Section {
+    fn add_bindings(&self, model: &mut Model, set: &'static str) -> Result<(), Error> {
+        if let Some(timeout) = self.timeout {
+            match duration(timeout).filter(|&timeout| timeout > Duration::from_secs(0)) {
+                Some(timeout) => model.set_timeout(set, timeout),
+                None => {
+                    return Err(Error::Invalid(format!(
+                        "Timeout for '{}' must be positive and at most a day",
+                        set
+                    )))
+                }
+            }
+        }
+        if self.show_help == Some(false) {
+            model.set_help_delay(set, None);
+        } else if let Some(delay) = self.help_delay {
+            match duration(delay) {
+                Some(delay) => model.set_help_delay(set, Some(delay)),
+                None => {
+                    return Err(Error::Invalid(format!(
+                        "Help delay for '{}' must not be negative or more than a day",
+                        set
+                    )))
+                }
+            }
+        }
+
+        let guards: Vec<&Guard> = self.guard.iter().collect();
+        for spec in &self.binding {
+            spec.add_binding(model, set, None, &guards)?;
+        }
+        for group in &self.group {
+            let mut guards = guards.clone();
+            guards.extend(group.guard.iter());
+            for spec in &group.binding {
+                spec.add_binding(model, set, Some(intern(&group.name)), &guards)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Group {
+    name: String,
+    guard: Option<Guard>,
+    #[serde(default)]
+    binding: Vec<BindingSpec>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Guard {
+    class: Option<String>,
+    instance: Option<String>,
+    title: Option<String>,
+}
+
+impl Guard {
+    // Titles change as applications work, so the title only has to contain the text
+    fn matches(&self, context: &Context) -> bool {
+        self.class.iter().all(|class| *class == context.class())
+            && self
+                .instance
+                .iter()
+                .all(|instance| *instance == context.instance())
+            && self
+                .title
+                .iter()
+                .all(|title| context.title().contains(title))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BindingSpec {
+    key: String,
+    label: String,
+    description: Option<String>,
+    guard: Option<Guard>,
+    #[serde(default)]
+    cancel: bool,
+    #[serde(default)]
+    toggle_help: bool,
+    #[serde(default)]
+    search_help: bool,
+    #[serde(default)]
+    palette: bool,
+    #[serde(default)]
+    launch: bool,
+    mode: Option<String>,
+    exec: Option<String>,
+    ceramic: Option<String>,
+    ewmh: Option<String>,
+    #[serde(default)]
+    hydra: bool,
+}
+
+impl BindingSpec {
+    fn add_binding(
+        &self,
+        model: &mut Model,
+        set: &'static str,
+        group: Option<&'static str>,
+        guards: &[&Guard],
+    ) -> Result<(), Error> {
+        // A sequence of keys is separated by commas e.g. "Ctrl + x, Ctrl + f"
+        let mut keys = self
+            .key
+            .split(',')
+            .map(KeySpec::parse)
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| Error::Invalid(format!("Key '{}' is not valid", self.key)))?;
+        let key = keys.pop().unwrap();
+        let set = model.add_prefix_modes(set, group, keys);
+
+        let guards: Vec<Guard> = guards
+            .iter()
+            .copied()
+            .chain(self.guard.iter())
+            .cloned()
+            .collect();
+        let guard = if guards.is_empty() {
+            None
+        } else {
+            Some(new_guardfn(move |context: &Context| {
+                guards.iter().all(|g| g.matches(context))
+            }))
+        };
+
+        let description = self.description.as_ref().map(|d| intern(d.trim()));
+        model.add_binding(
+            set,
+            key,
+            intern(&self.label),
+            description,
+            group,
+            guard,
+            self.action()?,
+        );
+        Ok(())
+    }
+
+    fn action(&self) -> Result<Action, Error> {
+        let mut actions = Vec::new();
+        if self.cancel {
+            actions.push(Action::Cancel);
+        }
+        if self.toggle_help {
+            actions.push(Action::ToggleHelp);
+        }
+        if self.search_help {
+            actions.push(Action::SearchHelp);
+        }
+        if self.palette {
+            actions.push(Action::Palette);
+        }
+        if self.launch {
+            actions.push(Action::Launch);
+        }
+        if let Some(mode) = &self.mode {
+            actions.push(Action::Mode(intern(mode)));
+        }
+        if let Some(command) = &self.exec {
+            let command = command.clone();
+            actions.push(self.make_action(new_actionfn(move |_ctx: &Context| {
+                if let Some(command) = window_picker::expand_placeholders(&command) {
+                    process::spawn(&command)
+                }
+            })));
+        }
+        if let Some(command) = &self.ceramic {
+            let command = command.clone();
+            actions.push(self.make_action(new_actionfn(move |_ctx: &Context| {
+                ceramic::ceramic_do(&command)
+            })));
+        }
+        if let Some(command) = &self.ewmh {
+            let command = command.clone();
+            actions.push(
+                self.make_action(new_actionfn(move |_ctx: &Context| ewmh::ewmh_do(&command))),
+            );
+        }
+
+        match actions.len() {
+            1 => Ok(actions.remove(0)),
+            _ => Err(Error::Invalid(format!(
+                "Binding '{}' must have exactly one of cancel, toggle_help, search_help, palette, launch, mode, exec, ceramic or ewmh",
+                self.key
+            ))),
+        }
+    }
+
+    fn make_action(&self, f: std::sync::Arc<Box<dyn ActionFn>>) -> Action {
+        if self.hydra {
+            Action::Call(f)
+        } else {
+            Action::Exec(f)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(spec: &str) -> Result<Action, Error> {
+        toml::from_str::<BindingSpec>(spec).unwrap().action()
+    }
+
+    #[test]
+    fn binding_has_its_one_action() {
+        match action("key = 'a'\nlabel = 'A'\nmode = 'window'") {
+            Ok(Action::Mode(mode)) => assert_eq!(mode, "window"),
+            _ => panic!("expected a mode"),
+        }
+        match action("key = 'a'\nlabel = 'A'\ncancel = true") {
+            Ok(Action::Cancel) => {}
+            _ => panic!("expected cancel"),
+        }
+        match action("key = 'a'\nlabel = 'A'\ntoggle_help = true") {
+            Ok(Action::ToggleHelp) => {}
+            _ => panic!("expected toggle_help"),
+        }
+    }
+
+    #[test]
+    fn hydra_bindings_stay_in_their_mode() {
+        match action("key = 'a'\nlabel = 'A'\nexec = 'true'") {
+            Ok(Action::Exec(_)) => {}
+            _ => panic!("expected exec"),
+        }
+        match action("key = 'a'\nlabel = 'A'\nceramic = 'quit'\nhydra = true") {
+            Ok(Action::Call(_)) => {}
+            _ => panic!("expected call"),
+        }
+    }
+
+    #[test]
+    fn binding_without_an_action_is_invalid() {
+        for spec in &[
+            "key = 'a'\nlabel = 'A'",
+            "key = 'a'\nlabel = 'A'\nhydra = true",
+        ] {
+            match action(spec) {
+                Err(Error::Invalid(message)) => assert!(message.contains("exactly one")),
+                _ => panic!("expected an error for {}", spec),
+            }
+        }
+    }
+
+    #[test]
+    fn binding_with_two_actions_is_invalid() {
+        for spec in &[
+            "key = 'a'\nlabel = 'A'\ncancel = true\ntoggle_help = true",
+            "key = 'a'\nlabel = 'A'\nmode = 'window'\nexec = 'true'",
+            "key = 'a'\nlabel = 'A'\nexec = 'true'\nceramic = 'quit'",
+        ] {
+            match action(spec) {
+                Err(Error::Invalid(_)) => {}
+                _ => panic!("expected an error for {}", spec),
+            }
+        }
+    }
+
+    #[test]
+    fn configuration_is_read_as_documented() {
+        let config: Config = toml::from_str(
+            r#"
+            [[global.binding]]
+            key = "Escape"
+            label = "Cancel Operation"
+            cancel = true
+
+            [root]
+            guard = { class = "Emacs" }
+
+            [[root.group]]
+            name = "Focus"
+            [[root.group.binding]]
+            key = "Cmd + j"
+            label = "Next"
+            ceramic = "focus_on_next_window"
+
+            [[mode."window_manager::launch".binding]]
+            key = "t"
+            label = "Terminal"
+            exec = "rxvt"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.global.unwrap().binding[0].key, "Escape");
+        let root = config.root.unwrap();
+        assert_eq!(root.guard.unwrap().class, Some("Emacs".to_owned()));
+        assert_eq!(root.group[0].name, "Focus");
+        assert_eq!(root.group[0].binding[0].label, "Next");
+        assert_eq!(config.mode["window_manager::launch"].binding.len(), 1);
+    }
+
+    #[test]
+    fn unknown_and_missing_keys_are_errors() {
+        for string in &[
+            "[rooot]",
+            "[root]\nguards = { class = 'Emacs' }",
+            "[root]\nguard = { klass = 'Emacs' }",
+            "[[root.group]]\nname = 'Focus'\nlabel = 'Focus'",
+            "[[root.group]]\nbinding = []",
+            "[[root.binding]]\nkey = 'a'\nlabel = 'A'\ncancel = true\nexecute = 'true'",
+            "[[root.binding]]\nlabel = 'A'\ncancel = true",
+            "[[root.binding]]\nkey = 'a'\nlabel = 'A'\ncancel = 'yes'",
+        ] {
+            assert!(toml::from_str::<Config>(string).is_err(), "{}", string);
+        }
+    }
+
+    #[test]
+    fn guard_matches_class_instance_and_part_of_title() {
+        let context = |instance, class, title| Context::with_names(instance, class, title);
+        let guard: Guard = toml::from_str("class = 'Emacs'\ntitle = 'scratch'").unwrap();
+        assert!(guard.matches(&context("emacs", "Emacs", "*scratch* - GNU Emacs")));
+        assert!(!guard.matches(&context("emacs", "Emacs", "init.el - GNU Emacs")));
+        assert!(!guard.matches(&context("xterm", "XTerm", "scratch")));
+        let guard: Guard = toml::from_str("instance = 'emacs'").unwrap();
+        assert!(guard.matches(&context("emacs", "Emacs", "")));
+        assert!(!guard.matches(&context("Emacs", "Emacs", "")));
+        let guard: Guard = toml::from_str("").unwrap();
+        assert!(guard.matches(&Context::default()));
+    }
+
+    #[test]
+    fn launch_is_an_action_of_its_own() {
+        match action("key = 'a'\nlabel = 'A'\nlaunch = true") {
+            Ok(Action::Launch) => {}
+            _ => panic!("expected launch"),
+        }
+        match action("key = 'a'\nlabel = 'A'\nlaunch = true\nexec = 'true'") {
+            Err(Error::Invalid(_)) => {}
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn durations_are_finite_and_at_most_a_day() {
+        assert_eq!(duration(1.5), Some(Duration::from_millis(1500)));
+        assert_eq!(duration(0.0), Some(Duration::from_secs(0)));
+        assert_eq!(
+            duration(MAX_SECONDS),
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+        for &seconds in &[
+            -1.0,
+            MAX_SECONDS + 1.0,
+            std::f64::NAN,
+            std::f64::INFINITY,
+            std::f64::NEG_INFINITY,
+        ] {
+            assert_eq!(duration(seconds), None, "{}", seconds);
+        }
+    }
+
+    fn add_bindings(string: &str) -> Result<Model, Error> {
+        let mut model = Model::unresolved();
+        toml::from_str::<Config>(string)?.add_bindings(&mut model)?;
+        Ok(model)
+    }
+
+    #[test]
+    fn sections_and_groups_become_bindings() {
+        use itertools::Itertools;
+
+        let model = add_bindings(
+            r#"
+            [[root.binding]]
+            key = "Cmd + a"
+            label = "Applications"
+            mode = "applications"
+
+            [[root.group]]
+            name = "Files"
+            guard = { class = "Emacs" }
+            [[root.group.binding]]
+            key = "Ctrl + x, Ctrl + f"
+            label = "Find File"
+            exec = "emacsclient --eval '(find-file)'"
+
+            [[mode.applications.binding]]
+            key = "t"
+            label = "Terminal"
+            exec = "xterm"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(model.get_modes(), ["@root", "applications"]);
+
+        let sequences: Vec<(String, &str, Option<&str>)> = model
+            .get_key_sequences("@root")
+            .iter()
+            .map(|(keys, b)| (keys.iter().join(", "), b.label(), b.group()))
+            .collect();
+        assert_eq!(
+            sequences,
+            [
+                ("Super + a".to_owned(), "Applications", None),
+                (
+                    "Control + x, Control + f".to_owned(),
+                    "Find File",
+                    Some("Files")
+                ),
+            ]
+        );
+        assert_eq!(model.get_key_sequences("applications").len(), 1);
+    }
+
+    #[test]
+    fn invalid_sections_are_errors() {
+        for string in &[
+            "[[root.binding]]\nkey = 'Cmd +'\nlabel = 'A'\ncancel = true",
+            "[[root.binding]]\nkey = 'a,'\nlabel = 'A'\ncancel = true",
+            "[mode.hydra]\ntimeout = 0.0",
+            "[mode.hydra]\ntimeout = nan",
+            "[mode.hydra]\nhelp_delay = -1.0",
+            "[help]\ndelay = inf",
+        ] {
+            match add_bindings(string) {
+                Err(Error::Invalid(_)) => {}
+                _ => panic!("expected an error for {}", string),
+            }
+        }
+    }
+}
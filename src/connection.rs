@@ -1,38 +1,518 @@
 use crate::keystroke::Keystroke;
 use cairo::XCBSurface;
-use std::collections::HashSet;
+use lazy_static::lazy_static;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
 
 pub fn connection() -> &'static xcb::Connection {
-    static mut CONNECTION: Option<xcb::Connection> = None;
-    unsafe {
-        CONNECTION.get_or_insert_with(|| {
-            let (connection, _screen_number) = xcb::Connection::connect(None).unwrap();
-            connection
-        })
+    try_connection().expect("Failed to connect to the X server")
+}
+
+// None without a display, which only exporting can do without
+pub fn try_connection() -> Option<&'static xcb::Connection> {
+    lazy_static! {
+        static ref CONNECTION: Option<xcb::Connection> = xcb::Connection::connect(None)
+            .ok()
+            .map(|(connection, _screen_number)| connection);
     }
+    CONNECTION.as_ref()
 }
 
-pub fn modifier_keycodes() -> &'static HashSet<xcb::xproto::Keycode> {
-    static mut MODIFIER_KEYCODES: Option<HashSet<xcb::xproto::Keycode>> = None;
-    unsafe {
-        MODIFIER_KEYCODES.get_or_insert_with(|| {
-            let connection = connection();
-            let mmc = xcb::xproto::get_modifier_mapping(&connection);
-            let mm = mmc.get_reply().unwrap();
-            let width = mm.keycodes_per_modifier();
-            let keycodes = mm.keycodes();
-            let mut seen = HashSet::new();
-            for mod_index in 0..8 {
-                for j in 0..width {
-                    let keycode = keycodes[mod_index * (width as usize) + (j as usize)];
-                    if keycode != 0 {
-                        seen.insert(keycode);
+// Derived from the keyboard mapping, so they're reset when it changes. Other
+// threads may still be using the old ones, so each caller gets a snapshot.
+lazy_static! {
+    static ref MODIFIER_KEYCODES: RwLock<Option<Arc<HashSet<xcb::xproto::Keycode>>>> =
+        RwLock::new(None);
+    static ref MODIFIER_MAP: RwLock<Option<Arc<ModifierMap>>> = RwLock::new(None);
+}
+
+fn cached<T>(cache: &RwLock<Option<Arc<T>>>, make: impl FnOnce() -> T) -> Arc<T> {
+    if let Some(value) = &*cache.read().unwrap() {
+        return value.clone();
+    }
+    cache
+        .write()
+        .unwrap()
+        .get_or_insert_with(|| Arc::new(make()))
+        .clone()
+}
+
+pub fn modifier_keycodes() -> Arc<HashSet<xcb::xproto::Keycode>> {
+    cached(&MODIFIER_KEYCODES, || {
+        modifier_mapping()
+            .into_iter()
+            .flatten()
+            .filter(|&keycode| keycode != 0)
+            .collect()
+    })
+}
+
+// Which modifier bits the keyboard layout uses for Alt, Super, Hyper etc., derived
+// from the keysyms on the keys in the modifier mapping
+pub struct ModifierMap {
+    masks: HashMap<&'static str, u16>,
+    names: [&'static str; 8], // the preferred name for each bit
+}
+
+// In order of preference when several share a bit
+const MODIFIER_NAMES: &[&str] = &[
+    "Shift",
+    "CapsLock",
+    "Control",
+    "Super",
+    "Hyper",
+    "Alt",
+    "Meta",
+    "NumLock",
+    "ISO_Level3_Shift",
+    "ScrollLock",
+];
+
+impl ModifierMap {
+    fn new() -> ModifierMap {
+        let key_symbols = xcb_util::keysyms::KeySymbols::new(connection());
+
+        let mut masks = HashMap::new();
+        let mut names = [
+            "Shift", "Lock", "Control", "Mod1", "Mod2", "Mod3", "Mod4", "Mod5",
+        ];
+
+        for (index, keycodes) in modifier_mapping().into_iter().enumerate() {
+            let bit = 1 << index;
+            let mut bit_names = HashSet::new();
+            for keycode in keycodes.into_iter().filter(|&keycode| keycode != 0) {
+                for column in 0..2 {
+                    if let Some(name) = modifier_name(key_symbols.get_keysym(keycode, column)) {
+                        bit_names.insert(name);
                     }
                 }
             }
-            seen
+            for &name in &bit_names {
+                *masks.entry(name).or_insert(0) |= bit;
+            }
+            if let Some(&name) = MODIFIER_NAMES.iter().find(|name| bit_names.contains(*name)) {
+                names[index] = name;
+            }
+        }
+
+        // The core protocol fixes these
+        masks.insert("Shift", xcb::MOD_MASK_SHIFT as u16);
+        masks.insert("Control", xcb::MOD_MASK_CONTROL as u16);
+
+        log::debug!("Modifier masks {:?}", masks);
+        ModifierMap { masks, names }
+    }
+
+    // The bits for a modifier name such as "Hyper", or None if no key produces it
+    pub fn mask(&self, name: &str) -> Option<u16> {
+        self.masks.get(name).copied()
+    }
+
+    // The preferred name for each bit in a mask, from the least significant bit
+    pub fn names(&self, mask: u16) -> Vec<&'static str> {
+        (0..8)
+            .filter(|index| mask & (1 << index) != 0)
+            .map(|index| self.names[index])
+            .collect()
+    }
+}
+
+pub fn modifier_map() -> Arc<ModifierMap> {
+    cached(&MODIFIER_MAP, ModifierMap::new)
+}
+
+pub fn forget_keyboard_mapping() {
+    *MODIFIER_KEYCODES.write().unwrap() = None;
+    *MODIFIER_MAP.write().unwrap() = None;
+}
+
+// Ask for XKB notifications of a new keyboard or a changed keymap, which unlike core
+// MappingNotify events are sent when e.g. setxkbmap runs. Returns the event code
+// for XKB events, if the extension is available.
+pub fn select_keyboard_events() -> Option<u8> {
+    use xcb::xkb;
+    let connection = connection();
+    let first_event = connection
+        .get_extension_data(xkb::id())
+        .filter(|data| data.present())?
+        .first_event();
+    xkb::use_extension(connection, 1, 0).get_reply().ok()?;
+
+    let events = (xkb::EVENT_TYPE_NEW_KEYBOARD_NOTIFY | xkb::EVENT_TYPE_MAP_NOTIFY) as u16;
+    let map_parts =
+        (xkb::MAP_PART_KEY_TYPES | xkb::MAP_PART_KEY_SYMS | xkb::MAP_PART_MODIFIER_MAP) as u16;
+    xkb::select_events_checked(
+        connection,
+        xkb::ID_USE_CORE_KBD as u16,
+        events,
+        0,
+        events,
+        map_parts,
+        map_parts,
+        None,
+    )
+    .request_check()
+    .ok()?;
+
+    Some(first_event)
+}
+
+fn modifier_name(keysym: xcb::Keysym) -> Option<&'static str> {
+    use xkbcommon::xkb;
+    match keysym {
+        xkb::KEY_Shift_L | xkb::KEY_Shift_R => Some("Shift"),
+        xkb::KEY_Caps_Lock | xkb::KEY_Shift_Lock => Some("CapsLock"),
+        xkb::KEY_Control_L | xkb::KEY_Control_R => Some("Control"),
+        xkb::KEY_Alt_L | xkb::KEY_Alt_R => Some("Alt"),
+        xkb::KEY_Meta_L | xkb::KEY_Meta_R => Some("Meta"),
+        xkb::KEY_Super_L | xkb::KEY_Super_R => Some("Super"),
+        xkb::KEY_Hyper_L | xkb::KEY_Hyper_R => Some("Hyper"),
+        xkb::KEY_Num_Lock => Some("NumLock"),
+        xkb::KEY_Scroll_Lock => Some("ScrollLock"),
+        xkb::KEY_ISO_Level3_Shift | xkb::KEY_Mode_switch => Some("ISO_Level3_Shift"),
+        _ => None,
+    }
+}
+
+// The keycodes for each of the eight modifiers, Shift, Lock, Control and Mod1-5
+fn modifier_mapping() -> Vec<Vec<xcb::xproto::Keycode>> {
+    let reply = xcb::xproto::get_modifier_mapping(connection())
+        .get_reply()
+        .unwrap();
+    // A server with no modifier keys at all reports none per modifier
+    match reply.keycodes_per_modifier() as usize {
+        0 => vec![Vec::new(); 8],
+        keycodes_per_modifier => reply
+            .keycodes()
+            .chunks(keycodes_per_modifier)
+            .map(|keycodes| keycodes.to_vec())
+            .collect(),
+    }
+}
+
+pub fn intern_atom(name: &str) -> xcb::Atom {
+    match xcb::intern_atom(connection(), false, name).get_reply() {
+        Ok(reply) => reply.atom(),
+        Err(_) => {
+            log::error!("Failed to intern atom {}", name);
+            xcb::ATOM_NONE
+        }
+    }
+}
+
+pub fn get_property(
+    window: xcb::Window,
+    property: xcb::Atom,
+    type_: xcb::Atom,
+) -> Option<xcb::GetPropertyReply> {
+    xcb::get_property(
+        connection(),
+        false,
+        window,
+        property,
+        type_,
+        0,
+        std::u32::MAX,
+    )
+    .get_reply()
+    .ok()
+    .filter(|reply| reply.type_() != xcb::ATOM_NONE)
+}
+
+pub fn get_string_property(window: xcb::Window, property: xcb::Atom) -> Option<String> {
+    get_property(window, property, xcb::GET_PROPERTY_TYPE_ANY)
+        .filter(|reply| reply.format() == 8)
+        .map(|reply| String::from_utf8_lossy(reply.value::<u8>()).into_owned())
+}
+
+pub fn get_u32_property(window: xcb::Window, property: xcb::Atom, type_: xcb::Atom) -> Option<u32> {
+    get_property(window, property, type_)
+        .filter(|reply| reply.format() == 32)
+        .and_then(|reply| reply.value::<u32>().first().copied())
+}
+
+// The top-level window that has the focus, preferring the window manager's idea
+// of the active window and otherwise walking up from the input focus to the first
+// window that has a WM_CLASS.
+pub fn focused_window() -> Option<xcb::Window> {
+    let root = connection().get_setup().roots().nth(0).unwrap().root();
+
+    lazy_static! {
+        static ref NET_ACTIVE_WINDOW: xcb::Atom = intern_atom("_NET_ACTIVE_WINDOW");
+    }
+    let active_window = get_u32_property(root, *NET_ACTIVE_WINDOW, xcb::ATOM_WINDOW)
+        .filter(|&window| window != xcb::NONE);
+    if active_window.is_some() {
+        return active_window;
+    }
+
+    let mut window = xcb::get_input_focus(connection()).get_reply().ok()?.focus();
+    while window != xcb::NONE && window != xcb::INPUT_FOCUS_POINTER_ROOT as u32 && window != root {
+        if get_property(window, xcb::ATOM_WM_CLASS, xcb::ATOM_STRING).is_some() {
+            return Some(window);
+        }
+        window = xcb::query_tree(connection(), window)
+            .get_reply()
+            .ok()?
+            .parent();
+    }
+
+    None
+}
+
+// The position of the pointer on the root window
+pub fn pointer_position() -> Option<(i32, i32)> {
+    let root = connection().get_setup().roots().nth(0).unwrap().root();
+    let reply = xcb::query_pointer(connection(), root).get_reply().ok()?;
+    Some((reply.root_x() as i32, reply.root_y() as i32))
+}
+
+// The middle of a window, in root window coordinates
+pub fn window_center(window: xcb::Window) -> Option<(i32, i32)> {
+    let root = connection().get_setup().roots().nth(0).unwrap().root();
+    let geometry = xcb::get_geometry(connection(), window).get_reply().ok()?;
+    let origin = xcb::translate_coordinates(connection(), window, root, 0, 0)
+        .get_reply()
+        .ok()?;
+    Some((
+        origin.dst_x() as i32 + geometry.width() as i32 / 2,
+        origin.dst_y() as i32 + geometry.height() as i32 / 2,
+    ))
+}
+
+// The part of the root window that a monitor shows
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Monitor {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub dpi: Option<f64>, // from the physical size, if the monitor reports it
+}
+
+impl Monitor {
+    fn contains(&self, (x, y): (i32, i32)) -> bool {
+        x >= self.x
+            && y >= self.y
+            && x < self.x + self.width as i32
+            && y < self.y + self.height as i32
+    }
+}
+
+// The monitors that RandR has CRTCs enabled for, or the whole root window as a
+// single monitor if RandR isn't available
+pub fn monitors() -> Vec<Monitor> {
+    let root = connection().get_setup().roots().nth(0).unwrap().root();
+    match randr_monitors(root) {
+        Some(monitors) if !monitors.is_empty() => monitors,
+        _ => {
+            let geometry = xcb::get_geometry(connection(), root).get_reply().unwrap();
+            vec![Monitor {
+                x: 0,
+                y: 0,
+                width: geometry.width() as u32,
+                height: geometry.height() as u32,
+                dpi: None,
+            }]
+        }
+    }
+}
+
+fn randr_monitors(root: xcb::Window) -> Option<Vec<Monitor>> {
+    use xcb::randr;
+    let connection = connection();
+    connection
+        .get_extension_data(randr::id())
+        .filter(|data| data.present())?;
+    randr::query_version(connection, 1, 3).get_reply().ok()?;
+
+    let resources = randr::get_screen_resources_current(connection, root)
+        .get_reply()
+        .ok()?;
+    let cookies: Vec<_> = resources
+        .crtcs()
+        .iter()
+        .map(|&crtc| randr::get_crtc_info(connection, crtc, resources.config_timestamp()))
+        .collect();
+    Some(
+        cookies
+            .into_iter()
+            .filter_map(|cookie| cookie.get_reply().ok())
+            .filter(|crtc| crtc.width() > 0 && crtc.num_outputs() > 0)
+            .map(|crtc| {
+                let output = randr::get_output_info(
+                    connection,
+                    crtc.outputs()[0],
+                    resources.config_timestamp(),
+                )
+                .get_reply()
+                .ok();
+                Monitor {
+                    x: crtc.x() as i32,
+                    y: crtc.y() as i32,
+                    width: crtc.width() as u32,
+                    height: crtc.height() as u32,
+                    dpi: output.and_then(|output| {
+                        dpi(
+                            (crtc.width() as u32, crtc.height() as u32),
+                            (output.mm_width(), output.mm_height()),
+                        )
+                    }),
+                }
+            })
+            .collect(),
+    )
+}
+
+// Measured along the diagonal, which doesn't depend on whether the monitor is
+// rotated. Some monitors report their aspect ratio rather than their size.
+fn dpi((width, height): (u32, u32), (mm_width, mm_height): (u32, u32)) -> Option<f64> {
+    match (mm_width, mm_height) {
+        (0, _) | (_, 0) | (160, 90) | (160, 100) | (16, 9) | (16, 10) => None,
+        _ => {
+            let pixels = (width as f64).hypot(height as f64);
+            let inches = (mm_width as f64).hypot(mm_height as f64) / 25.4;
+            Some(pixels / inches)
+        }
+    }
+}
+
+// The resources that have been loaded with xrdb, as names and values
+pub fn resources() -> Vec<(String, String)> {
+    let root = connection().get_setup().roots().nth(0).unwrap().root();
+    get_string_property(root, xcb::ATOM_RESOURCE_MANAGER)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => {
+                    Some((name.trim().to_owned(), value.trim().to_owned()))
+                }
+                _ => None,
+            }
         })
+        .collect()
+}
+
+// The resolution that Xft, and so most applications, render text for
+pub fn xft_dpi() -> Option<f64> {
+    resources()
+        .into_iter()
+        .find(|(name, _)| name == "Xft.dpi")
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+// The monitor containing a point, or the first monitor if none does
+pub fn monitor_at(position: Option<(i32, i32)>) -> Monitor {
+    let monitors = monitors();
+    position
+        .and_then(|position| monitors.iter().find(|m| m.contains(position)).copied())
+        .unwrap_or(monitors[0])
+}
+
+// Ask RandR for notifications when monitors are added, removed or rearranged.
+// Returns the event code for screen change events, if the extension is available.
+pub fn select_screen_change_events() -> Option<u8> {
+    use xcb::randr;
+    let connection = connection();
+    let first_event = connection
+        .get_extension_data(randr::id())
+        .filter(|data| data.present())?
+        .first_event();
+    randr::query_version(connection, 1, 3).get_reply().ok()?;
+
+    let root = connection.get_setup().roots().nth(0).unwrap().root();
+    randr::select_input_checked(connection, root, randr::NOTIFY_MASK_SCREEN_CHANGE as u16)
+        .request_check()
+        .ok()?;
+
+    Some(first_event + randr::SCREEN_CHANGE_NOTIFY)
+}
+
+// A compositor owns this selection while it's running, and only then are windows
+// blended with what's behind them
+pub fn compositor_is_running() -> bool {
+    xcb::get_selection_owner(connection(), intern_atom("_NET_WM_CM_S0"))
+        .get_reply()
+        .map(|reply| reply.owner() != xcb::NONE)
+        .unwrap_or(false)
+}
+
+// The red, green and blue masks of the root window's visual
+pub fn root_visual_masks() -> (u32, u32, u32) {
+    let screen = connection().get_setup().roots().nth(0).unwrap();
+    let root_visual = screen.root_visual();
+    screen
+        .allowed_depths()
+        .flat_map(|d| d.visuals())
+        .find(|v| v.visual_id() == root_visual)
+        .map(|v| (v.red_mask(), v.green_mask(), v.blue_mask()))
+        .unwrap_or((0xff_0000, 0xff00, 0xff))
+}
+
+// A 32 bit TrueColor visual, whose alpha channel a compositor uses
+pub fn argb_visual() -> Option<xcb::Visualid> {
+    let screen = connection().get_setup().roots().nth(0).unwrap();
+    screen
+        .allowed_depths()
+        .filter(|d| d.depth() == 32)
+        .flat_map(|d| d.visuals())
+        .find(|v| v.class() == xcb::VISUAL_CLASS_TRUE_COLOR as u8)
+        .map(|v| v.visual_id())
+}
+
+// An unmapped window that other threads can send client messages to in order
+// to wake up the key dispatcher, which is otherwise blocked waiting for X events.
+pub fn message_window() -> xcb::Window {
+    lazy_static! {
+        static ref MESSAGE_WINDOW: xcb::Window = {
+            let connection = connection();
+            let root = connection.get_setup().roots().nth(0).unwrap().root();
+            let window = connection.generate_id();
+            xcb::create_window(
+                connection,
+                0,
+                window,
+                root,
+                0,
+                0,
+                1,
+                1,
+                0,
+                xcb::WINDOW_CLASS_INPUT_ONLY as u16,
+                xcb::COPY_FROM_PARENT,
+                &[],
+            );
+            connection.flush();
+            window
+        };
     }
+    *MESSAGE_WINDOW
+}
+
+pub fn send_message(message_type: xcb::Atom, data: u32) {
+    let window = message_window();
+    let event = xcb::ClientMessageEvent::new(
+        32,
+        window,
+        message_type,
+        xcb::ClientMessageData::from_data32([data, 0, 0, 0, 0]),
+    );
+    xcb::send_event(
+        connection(),
+        false,
+        window,
+        xcb::EVENT_MASK_NO_EVENT,
+        &event,
+    );
+    connection().flush();
 }
 
 pub fn grab_keys(keystrokes: &Vec<Keystroke>) {
@@ -62,7 +542,25 @@ pub fn ungrab_all_keys() {
     connection().flush();
 }
 
+// Replace the root grabs without a window where no keys are grabbed
+pub fn regrab_keys(keystrokes: &Vec<Keystroke>) {
+    xcb::xproto::grab_server(connection());
+    ungrab_all_keys();
+    grab_keys(keystrokes);
+    xcb::xproto::ungrab_server(connection());
+    connection().flush();
+}
+
+// Whether commando holds an active grab of the keyboard, so that nested event loops
+// such as picking a window can leave it as they found it
+static KEYBOARD_IS_GRABBED: AtomicBool = AtomicBool::new(false);
+
+pub fn keyboard_is_grabbed() -> bool {
+    KEYBOARD_IS_GRABBED.load(Ordering::SeqCst)
+}
+
 pub fn grab_keyboard() {
+    KEYBOARD_IS_GRABBED.store(true, Ordering::SeqCst);
     let root = connection().get_setup().roots().nth(0).unwrap().root();
     match xcb::xproto::grab_keyboard(
         connection(),
@@ -88,6 +586,7 @@ pub fn grab_keyboard() {
 
 pub fn ungrab_keyboard() {
     log::debug!("Ungrab keyboard");
+    KEYBOARD_IS_GRABBED.store(false, Ordering::SeqCst);
     xcb::xproto::ungrab_keyboard(connection(), xcb::CURRENT_TIME);
     connection().flush();
 }
@@ -101,7 +600,31 @@ pub fn allow_events() {
     connection().flush();
 }
 
+// Send a key that was frozen by a passive grab on to the focused window
+pub fn replay_keyboard() {
+    xcb::xproto::allow_events(
+        connection(),
+        xcb::ALLOW_REPLAY_KEYBOARD as u8,
+        xcb::CURRENT_TIME,
+    );
+    connection().flush();
+}
+
+// Events that a nested event loop, such as picking a window, didn't handle itself,
+// which are handed out again before new ones
+thread_local! {
+    static DEFERRED_EVENTS: RefCell<VecDeque<xcb::base::GenericEvent>> =
+        RefCell::new(VecDeque::new());
+}
+
+pub fn defer_event(event: xcb::base::GenericEvent) {
+    DEFERRED_EVENTS.with(|events| events.borrow_mut().push_back(event));
+}
+
 pub fn wait_for_event() -> Option<xcb::base::GenericEvent> {
+    if let Some(event) = DEFERRED_EVENTS.with(|events| events.borrow_mut().pop_front()) {
+        return Some(event);
+    }
     allow_events();
     connection().wait_for_event()
 }
@@ -118,12 +641,15 @@ pub fn get_cairo_surface(window: xcb::Window) -> Result<cairo::Surface, xcb::Gen
 
     let cairo_drawable = cairo::XCBDrawable(window);
 
+    // The window may have a visual of its own, for translucency
+    let visual_id = xcb::get_window_attributes(&connection, window)
+        .get_reply()?
+        .visual();
     let screen = connection.get_setup().roots().nth(0).unwrap();
     let mut visual = screen
         .allowed_depths()
-        .filter(|d| d.depth() == screen.root_depth())
         .flat_map(|d| d.visuals())
-        .find(|v| v.visual_id() == screen.root_visual())
+        .find(|v| v.visual_id() == visual_id)
         .unwrap();
     let cairo_visualtype = unsafe {
         cairo::XCBVisualType::from_raw_none(
@@ -0,0 +1,201 @@
+use super::{connection, model::*, window_picker};
+
+// Window management through EWMH client messages, for window managers other than
+// ceramic. Commands are strings like ceramic's so that they can come from the
+// configuration file, e.g. "switch_to_desktop: 2" or "close_window: {selected_window}".
+// Desktops are numbered from 1, and commands without a window apply to the focused
+// window.
+pub fn ewmh_do(cmd: &str) {
+    let cmd = match window_picker::expand_placeholders(cmd) {
+        Some(cmd) => cmd,
+        None => return,
+    };
+
+    log::debug!("EWMH command: {}", cmd);
+    let mut parts = cmd.splitn(2, ':');
+    let name = parts.next().unwrap_or_default().trim();
+    let argument = parts.next().map(str::trim).filter(|a| !a.is_empty());
+
+    let window = || match argument {
+        Some(argument) => argument.parse().ok(),
+        None => connection::focused_window(),
+    };
+    let desktop = || {
+        argument
+            .and_then(|a| a.parse::<u32>().ok())
+            .filter(|&d| d > 0)
+            .map(|d| d - 1)
+    };
+
+    let done = match name {
+        "activate_window" => window().map(activate_window),
+        "close_window" => window().map(close_window),
+        "toggle_fullscreen" => window().map(|w| toggle_state(w, "_NET_WM_STATE_FULLSCREEN", None)),
+        "toggle_maximized" => window().map(|w| {
+            toggle_state(
+                w,
+                "_NET_WM_STATE_MAXIMIZED_VERT",
+                Some("_NET_WM_STATE_MAXIMIZED_HORZ"),
+            )
+        }),
+        "switch_to_desktop" => desktop().map(switch_to_desktop),
+        "move_focused_window_to_desktop" => desktop()
+            .and_then(|d| connection::focused_window().map(|w| move_window_to_desktop(w, d))),
+        "focus_next_window" => Some(focus_relative_window(1)),
+        "focus_previous_window" => Some(focus_relative_window(-1)),
+        _ => None,
+    };
+
+    if done.is_none() {
+        log::error!("Invalid EWMH command: {}", cmd);
+    }
+}
+
+pub fn activate_window(window: xcb::Window) {
+    // Source indication 2 is a pager, which window managers don't second guess
+    send_root_message(
+        window,
+        "_NET_ACTIVE_WINDOW",
+        [2, xcb::CURRENT_TIME, 0, 0, 0],
+    );
+}
+
+pub fn close_window(window: xcb::Window) {
+    send_root_message(window, "_NET_CLOSE_WINDOW", [xcb::CURRENT_TIME, 2, 0, 0, 0]);
+}
+
+pub fn switch_to_desktop(desktop: u32) {
+    let root = connection::connection()
+        .get_setup()
+        .roots()
+        .nth(0)
+        .unwrap()
+        .root();
+    send_root_message(
+        root,
+        "_NET_CURRENT_DESKTOP",
+        [desktop, xcb::CURRENT_TIME, 0, 0, 0],
+    );
+}
+
+pub fn move_window_to_desktop(window: xcb::Window, desktop: u32) {
+    send_root_message(window, "_NET_WM_DESKTOP", [desktop, 2, 0, 0, 0]);
+}
+
+pub fn toggle_state(window: xcb::Window, state: &str, other_state: Option<&str>) {
+    const NET_WM_STATE_TOGGLE: u32 = 2;
+    send_root_message(
+        window,
+        "_NET_WM_STATE",
+        [
+            NET_WM_STATE_TOGGLE,
+            connection::intern_atom(state),
+            other_state.map_or(0, connection::intern_atom),
+            2,
+            0,
+        ],
+    );
+}
+
+// Activate the window `offset` places away from the active window in the client
+// list, considering only windows on the current desktop
+pub fn focus_relative_window(offset: isize) {
+    let root = connection::connection()
+        .get_setup()
+        .roots()
+        .nth(0)
+        .unwrap()
+        .root();
+
+    let current_desktop = connection::get_u32_property(
+        root,
+        connection::intern_atom("_NET_CURRENT_DESKTOP"),
+        xcb::ATOM_CARDINAL,
+    );
+    let desktop_atom = connection::intern_atom("_NET_WM_DESKTOP");
+    let windows: Vec<xcb::Window> = connection::get_property(
+        root,
+        connection::intern_atom("_NET_CLIENT_LIST"),
+        xcb::ATOM_WINDOW,
+    )
+    .map(|reply| reply.value::<u32>().to_vec())
+    .unwrap_or_default()
+    .into_iter()
+    .filter(|&window| {
+        // 0xFFFFFFFF means the window is on all desktops
+        match connection::get_u32_property(window, desktop_atom, xcb::ATOM_CARDINAL) {
+            Some(0xFFFF_FFFF) | None => true,
+            desktop => current_desktop.is_none() || desktop == current_desktop,
+        }
+    })
+    .collect();
+    if windows.is_empty() {
+        return;
+    }
+
+    let count = windows.len() as isize;
+    let index = connection::focused_window()
+        .and_then(|focused| windows.iter().position(|&w| w == focused))
+        .map_or(0, |index| {
+            ((index as isize + offset) % count + count) % count
+        });
+    activate_window(windows[index as usize]);
+}
+
+fn send_root_message(window: xcb::Window, message_type: &str, data: [u32; 5]) {
+    let connection = connection::connection();
+    let root = connection.get_setup().roots().nth(0).unwrap().root();
+    let event = xcb::ClientMessageEvent::new(
+        32,
+        window,
+        connection::intern_atom(message_type),
+        xcb::ClientMessageData::from_data32(data),
+    );
+    xcb::send_event(
+        connection,
+        false,
+        root,
+        xcb::EVENT_MASK_SUBSTRUCTURE_NOTIFY | xcb::EVENT_MASK_SUBSTRUCTURE_REDIRECT,
+        &event,
+    );
+    connection.flush();
+}
+
+pub fn extend_model(model: &mut Model) {
+    model.extend_with(&bindings! {
+        root {
+            Cmd + Backspace => { "Close Window" ewmh_do("close_window") }
+            Cmd + f => { "Toggle Fullscreen" ewmh_do("toggle_fullscreen") }
+            Cmd + m => { "Toggle Maximized" ewmh_do("toggle_maximized") }
+            Cmd + r => { "Launch" launch }
+            group "Focus" {
+                Cmd + Tab => { "Next" ewmh_do("focus_next_window") }
+                Cmd + Shift + Tab => { "Previous" ewmh_do("focus_previous_window") }
+                Cmd + j => { "Next" ewmh_do("focus_next_window") }
+                Cmd + k => { "Previous" ewmh_do("focus_previous_window") }
+                Cmd + 1 => { "Desktop 1" ewmh_do("switch_to_desktop: 1") }
+                Cmd + 2 => { "Desktop 2" ewmh_do("switch_to_desktop: 2") }
+                Cmd + 3 => { "Desktop 3" ewmh_do("switch_to_desktop: 3") }
+                Cmd + 4 => { "Desktop 4" ewmh_do("switch_to_desktop: 4") }
+                Cmd + 5 => { "Desktop 5" ewmh_do("switch_to_desktop: 5") }
+                Cmd + 6 => { "Desktop 6" ewmh_do("switch_to_desktop: 6") }
+                Cmd + 7 => { "Desktop 7" ewmh_do("switch_to_desktop: 7") }
+                Cmd + 8 => { "Desktop 8" ewmh_do("switch_to_desktop: 8") }
+                Cmd + 9 => { "Desktop 9" ewmh_do("switch_to_desktop: 9") }
+                Cmd + 0 => { "Select …" "Activate a window by typing the label shown over it" ewmh_do("activate_window: {selected_window}") }
+            }
+            group "Move" {
+                Cmd + Shift + 1 => { "To Desktop 1" ewmh_do("move_focused_window_to_desktop: 1") }
+                Cmd + Shift + 2 => { "To Desktop 2" ewmh_do("move_focused_window_to_desktop: 2") }
+                Cmd + Shift + 3 => { "To Desktop 3" ewmh_do("move_focused_window_to_desktop: 3") }
+                Cmd + Shift + 4 => { "To Desktop 4" ewmh_do("move_focused_window_to_desktop: 4") }
+                Cmd + Shift + 5 => { "To Desktop 5" ewmh_do("move_focused_window_to_desktop: 5") }
+                Cmd + Shift + 6 => { "To Desktop 6" ewmh_do("move_focused_window_to_desktop: 6") }
+                Cmd + Shift + 7 => { "To Desktop 7" ewmh_do("move_focused_window_to_desktop: 7") }
+                Cmd + Shift + 8 => { "To Desktop 8" ewmh_do("move_focused_window_to_desktop: 8") }
+                Cmd + Shift + 9 => { "To Desktop 9" ewmh_do("move_focused_window_to_desktop: 9") }
+                Cmd + Shift + Opt + Backspace => { "Close …" "Close a window picked by its label" ewmh_do("close_window: {selected_window}") }
+            }
+        }
+    })
+}
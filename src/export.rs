@@ -0,0 +1,484 @@
+use super::{
+    connection, help,
+    keystroke::KeySpec,
+    model::{Binding, Model},
+    theme::Theme,
+};
+use itertools::Itertools;
+use pango::LayoutExt;
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+use structopt::StructOpt;
+
+// The width of a landscape A4 page in points, which the columns of a printed
+// cheat-sheet are fitted to
+const PAGE_WIDTH: f64 = 842.0;
+
+// `commando export` writes a cheat-sheet of every binding, whether or not its guard
+// would apply
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    /// Format of the cheat-sheet, otherwise the extension of the output file, or
+    /// else markdown
+    #[structopt(
+        long = "format",
+        raw(possible_values = r#"&["markdown", "html", "svg", "pdf"]"#)
+    )]
+    format: Option<Format>,
+
+    /// File to write instead of standard output
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Format {
+    Markdown,
+    Html,
+    Svg,
+    Pdf,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string {
+            "markdown" | "md" => Ok(Format::Markdown),
+            "html" | "htm" => Ok(Format::Html),
+            "svg" => Ok(Format::Svg),
+            "pdf" => Ok(Format::Pdf),
+            _ => Err(format!("Format '{}' is not known", string)),
+        }
+    }
+}
+
+// The bindings of a mode by group, with those that have no group first as in the
+// help window, and each with the keys that lead to it from the mode
+struct Section {
+    title: &'static str,
+    groups: Vec<(Option<&'static str>, Vec<(Vec<KeySpec>, Binding)>)>,
+}
+
+fn sections(model: &Model) -> Vec<Section> {
+    model
+        .get_modes()
+        .into_iter()
+        .map(|mode| {
+            let mut sequences = model.get_key_sequences(mode);
+            sequences.sort_by_key(|(_, b)| b.group());
+            let groups = sequences
+                .into_iter()
+                .group_by(|(_, b)| b.group())
+                .into_iter()
+                .map(|(group, rows)| (group, rows.collect()))
+                .collect();
+            Section {
+                title: match mode {
+                    "@global" => "Global",
+                    "@root" => "Root",
+                    _ => mode,
+                },
+                groups,
+            }
+        })
+        .collect()
+}
+
+pub fn export(model: &Model, options: &Options) -> io::Result<()> {
+    let format = options
+        .format
+        .or_else(|| {
+            options
+                .output
+                .as_ref()
+                .and_then(|path| path.extension())
+                .and_then(|extension| extension.to_str())
+                .and_then(|extension| extension.parse().ok())
+        })
+        .unwrap_or(Format::Markdown);
+
+    let sections = sections(model);
+    // Without a display there are no resources to style it after
+    let resource_theme = match connection::try_connection() {
+        Some(_) => Theme::from_resources(),
+        None => Theme::default(),
+    };
+    let theme = model.get_help_settings().theme(resource_theme);
+    let bytes = match format {
+        Format::Markdown => markdown(&sections).into_bytes(),
+        Format::Html => html(&sections, &theme).into_bytes(),
+        Format::Svg | Format::Pdf => page(&sections, &theme, format)?,
+    };
+
+    match &options.output {
+        Some(path) => fs::write(path, bytes),
+        None => io::stdout().write_all(&bytes),
+    }
+}
+
+fn markdown(sections: &[Section]) -> String {
+    let mut text = String::from("# Bindings\n");
+    for section in sections {
+        write!(text, "\n## {}\n", section.title).unwrap();
+        for (group, rows) in &section.groups {
+            if let Some(group) = group {
+                write!(text, "\n### {}\n", group).unwrap();
+            }
+            text.push_str("\n| Keys | Binding | Description |\n| --- | --- | --- |\n");
+            for (keys, binding) in rows {
+                writeln!(
+                    text,
+                    "| {} | {} | {} |",
+                    keys.iter()
+                        .map(|key| format!("<kbd>{}</kbd>", markdown_cell(&key.display_form())))
+                        .join(", "),
+                    markdown_cell(binding.label()),
+                    binding.description().map_or(String::new(), markdown_cell)
+                )
+                .unwrap();
+            }
+        }
+    }
+    text
+}
+
+// Text for a cell of a Markdown table, which has to be on one line, with the
+// characters that would be read as formatting escaped
+fn markdown_cell(text: &str) -> String {
+    escape_html(text)
+        .lines()
+        .map(|line| {
+            line.chars()
+                .map(|c| match c {
+                    '\\' | '`' | '*' | '_' | '|' | '[' | ']' => format!("\\{}", c),
+                    _ => c.to_string(),
+                })
+                .collect::<String>()
+        })
+        .join("<br>")
+}
+
+// A standalone page, styled after the help window's theme
+fn html(sections: &[Section], theme: &Theme) -> String {
+    let family = |font: &str| {
+        pango::FontDescription::from_string(font)
+            .get_family()
+            .map_or(String::new(), |family| format!("\"{}\", ", family))
+    };
+
+    let mut text = String::new();
+    write!(
+        text,
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Bindings</title>
+<style>
+body {{ font-family: {font}sans-serif; color: {foreground}; background: {background}; margin: 2em; }}
+h1, h2 {{ color: {accent}; }}
+h2 {{ border-bottom: 1px solid {separator}; }}
+h3 {{ color: {group_foreground}; border-bottom: 1px solid {group_rule}; }}
+section {{ break-inside: avoid; }}
+table {{ border-collapse: collapse; margin-bottom: 1em; }}
+td {{ padding: 0.2em 1em 0.2em 0; vertical-align: top; }}
+kbd {{ font-family: {key_font}{symbol_font}monospace; white-space: nowrap; }}
+.description {{ white-space: pre-line; }}
+</style>
+</head>
+<body>
+<h1>Bindings</h1>
+"#,
+        font = family(&theme.font),
+        key_font = family(&theme.key_font),
+        symbol_font = family(&theme.symbol_font),
+        foreground = theme.foreground.to_hex(),
+        background = theme.background.to_hex(),
+        accent = theme.accent.to_hex(),
+        separator = theme.separator.to_hex(),
+        group_foreground = theme.group_foreground.to_hex(),
+        group_rule = theme.group_rule.to_hex(),
+    )
+    .unwrap();
+
+    for section in sections {
+        write!(text, "<section>\n<h2>{}</h2>\n", escape_html(section.title)).unwrap();
+        for (group, rows) in &section.groups {
+            if let Some(group) = group {
+                writeln!(text, "<h3>{}</h3>", escape_html(group)).unwrap();
+            }
+            text.push_str("<table>\n");
+            for (keys, binding) in rows {
+                writeln!(
+                    text,
+                    r#"<tr><td>{}</td><td>{}</td><td class="description">{}</td></tr>"#,
+                    keys.iter()
+                        .map(|key| format!("<kbd>{}</kbd>", escape_html(&key.display_form())))
+                        .join(", "),
+                    escape_html(binding.label()),
+                    binding.description().map_or(String::new(), escape_html)
+                )
+                .unwrap();
+            }
+            text.push_str("</table>\n");
+        }
+        text.push_str("</section>\n");
+    }
+    text.push_str("</body>\n</html>\n");
+    text
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// The page is drawn once to a recording surface to find its size, and then played
+// back onto an SVG or PDF surface of that size
+fn page(sections: &[Section], theme: &Theme, format: Format) -> io::Result<Vec<u8>> {
+    let error = |message: &str| io::Error::new(io::ErrorKind::Other, message);
+
+    let recording = cairo::RecordingSurface::create(cairo::Content::ColorAlpha, None)
+        .ok_or_else(|| error("Can't create a recording surface"))?;
+    let (width, height) = draw_page(&cairo::Context::new(&*recording), sections, theme)
+        .ok_or_else(|| error("Can't lay out text"))?;
+
+    Ok(match format {
+        Format::Svg => {
+            let surface = cairo::svg::Writer::new(width, height, Vec::new());
+            play_back(&surface, &recording, theme);
+            surface.finish()
+        }
+        _ => {
+            let surface = cairo::pdf::Writer::new(width, height, Vec::new());
+            play_back(&surface, &recording, theme);
+            surface.finish()
+        }
+    })
+}
+
+fn play_back<T: AsRef<cairo::Surface>>(
+    surface: &T,
+    recording: &cairo::RecordingSurface,
+    theme: &Theme,
+) {
+    let cairo_context = cairo::Context::new(surface);
+    theme.background.set_source(&cairo_context);
+    cairo_context.paint();
+    cairo_context.set_source_surface(&**recording, 0.0, 0.0);
+    cairo_context.paint();
+    cairo_context.show_page();
+}
+
+// Groups of bindings that are kept together in a column, the first of each mode
+// under the mode's title
+struct Block<'a> {
+    title: Option<&'static str>,
+    group: Option<&'static str>,
+    rows: &'a [(Vec<KeySpec>, Binding)],
+    height: f64,
+}
+
+// Columns of groups, as many as fit across a page and about as tall as each other.
+// Returns the size of the page.
+fn draw_page(
+    cairo_context: &cairo::Context,
+    sections: &[Section],
+    theme: &Theme,
+) -> Option<(f64, f64)> {
+    let layout = pangocairo::functions::create_layout(cairo_context)?;
+    let description_layout = pangocairo::functions::create_layout(cairo_context)?;
+    let font_description = pango::FontDescription::from_string(&theme.font);
+    let key_font_description = pango::FontDescription::from_string(&theme.key_font);
+    let symbol_font_description = pango::FontDescription::from_string(&theme.symbol_font);
+    let (p, g) = (theme.padding as f64, theme.group_spacing as f64);
+    let r = help::row_height(
+        &layout,
+        &[
+            &font_description,
+            &key_font_description,
+            &symbol_font_description,
+        ],
+    ) as f64;
+
+    let rows = || {
+        sections
+            .iter()
+            .flat_map(|section| &section.groups)
+            .flat_map(|(_, rows)| rows)
+    };
+    let keys_width = rows()
+        .map(|(keys, _)| {
+            help::process_keys_help(
+                keys,
+                cairo_context,
+                &font_description,
+                &key_font_description,
+                &symbol_font_description,
+                false,
+            )
+        })
+        .max()
+        .unwrap_or(0) as f64;
+    layout.set_font_description(&font_description);
+    let label_width = rows()
+        .map(|(_, binding)| {
+            layout.set_text(binding.label());
+            layout.get_pixel_size().0
+        })
+        .max()
+        .unwrap_or(0)
+        .max(200) as f64;
+    let column_width = keys_width + p + label_width;
+
+    // Descriptions are wrapped below their labels
+    description_layout.set_font_description(&font_description);
+    description_layout.set_width(label_width as i32 * pango::SCALE);
+    description_layout.set_wrap(pango::WrapMode::WordChar);
+    let row_height = |binding: &Binding| match binding.description() {
+        Some(description) => {
+            description_layout.set_text(description);
+            r + description_layout.get_pixel_size().1 as f64
+        }
+        None => r,
+    };
+
+    let mut blocks = Vec::new();
+    for section in sections {
+        for (index, (group, rows)) in section.groups.iter().enumerate() {
+            let title = Some(section.title).filter(|_| index == 0);
+            let height = title.map_or(0.0, |_| r + p)
+                + group.map_or(0.0, |_| g + r)
+                + rows.iter().map(|(_, b)| row_height(b)).sum::<f64>();
+            blocks.push(Block {
+                title,
+                group: *group,
+                rows,
+                height,
+            });
+        }
+    }
+
+    // Move on to the next column once a block would end further below the average
+    // height than it started above it
+    let count = (((PAGE_WIDTH - p) / (column_width + p)) as usize)
+        .max(1)
+        .min(blocks.len().max(1));
+    let average = blocks.iter().map(|block| block.height).sum::<f64>() / count as f64;
+    let mut columns: Vec<Vec<&Block>> = vec![vec![]];
+    let mut y = 0.0;
+    for block in &blocks {
+        if y > 0.0 && y + block.height / 2.0 > average && columns.len() < count {
+            columns.push(vec![]);
+            y = 0.0;
+        }
+        columns.last_mut().unwrap().push(block);
+        y += block.height;
+    }
+
+    let mut tallest: f64 = 0.0;
+    for (index, column) in columns.iter().enumerate() {
+        let x = p + index as f64 * (column_width + p);
+        let mut y = p;
+        for block in column {
+            if let Some(title) = block.title {
+                theme.accent.set_source(cairo_context);
+                layout.set_font_description(&font_description);
+                layout.set_text(title);
+                cairo_context.move_to(x, y);
+                pangocairo::functions::show_layout(cairo_context, &layout);
+                y += r;
+
+                theme.separator.set_source(cairo_context);
+                cairo_context.move_to(x, (y + p / 2.0).floor() + 0.5);
+                cairo_context.rel_line_to(column_width, 0.0);
+                cairo_context.set_line_width(1.0);
+                cairo_context.stroke();
+                y += p;
+            }
+
+            if let Some(group) = block.group {
+                y += g;
+                theme.group_foreground.set_source(cairo_context);
+                layout.set_font_description(&font_description);
+                layout.set_text(group);
+                cairo_context.move_to(x, y);
+                pangocairo::functions::show_layout(cairo_context, &layout);
+
+                theme.group_rule.set_source(cairo_context);
+                cairo_context.move_to(x, (y + r).floor() - 0.5);
+                cairo_context.rel_line_to(column_width, 0.0);
+                cairo_context.set_line_width(1.0);
+                cairo_context.stroke();
+                y += r;
+            }
+
+            theme.foreground.set_source(cairo_context);
+            for (keys, binding) in block.rows {
+                cairo_context.move_to(x, y);
+                help::process_keys_help(
+                    keys,
+                    cairo_context,
+                    &font_description,
+                    &key_font_description,
+                    &symbol_font_description,
+                    true,
+                );
+
+                layout.set_font_description(&font_description);
+                layout.set_text(binding.label());
+                cairo_context.move_to(x + keys_width + p, y);
+                pangocairo::functions::show_layout(cairo_context, &layout);
+
+                if let Some(description) = binding.description() {
+                    description_layout.set_text(description);
+                    cairo_context.move_to(x + keys_width + p, y + r);
+                    pangocairo::functions::show_layout(cairo_context, &description_layout);
+                }
+                y += row_height(binding);
+            }
+        }
+        tallest = tallest.max(y);
+    }
+
+    let width = p + columns.len() as f64 * (column_width + p);
+    Some((width, tallest + p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_html_escapes_markup_characters() {
+        assert_eq!(
+            escape_html(r#"a < b & "c" > d"#),
+            "a &lt; b &amp; &quot;c&quot; &gt; d"
+        );
+        assert_eq!(escape_html("&lt;"), "&amp;lt;");
+        assert_eq!(escape_html("Cut"), "Cut");
+    }
+
+    #[test]
+    fn markdown_cell_escapes_formatting() {
+        assert_eq!(
+            markdown_cell("*bold* _em_ `code`"),
+            r"\*bold\* \_em\_ \`code\`"
+        );
+        assert_eq!(markdown_cell(r"[a|b] \ c"), r"\[a\|b\] \\ c");
+        assert_eq!(markdown_cell("<kbd>"), "&lt;kbd&gt;");
+    }
+
+    #[test]
+    fn markdown_cell_is_one_line() {
+        assert_eq!(markdown_cell("one\ntwo\r\nthree"), "one<br>two<br>three");
+    }
+}
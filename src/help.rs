@@ -1,81 +1,243 @@
 use crate::{
-    connection,
-    keystroke::Keystroke,
+    config, connection,
+    keystroke::{KeySpec, Keystroke},
     model::{Action, Binding},
+    prompt::{Prompt, PROMPT_ROWS},
+    theme::{Theme, ThemeSettings},
 };
-use crossbeam::channel::{Receiver, RecvTimeoutError};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use pango::LayoutExt;
-use std::collections::{BTreeMap, HashMap};
-use std::time::Duration;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 pub enum HelpMessage {
-    Arm,
+    Arm(Duration), // show the window after the delay, unless a key is pressed first
     Disarm,
-    Update(Vec<Binding>),
+    Configure(Settings),
+    Update(Option<&'static str>, Vec<Binding>),
+    Prompt(Prompt),
     Draw,
+    Reposition,
+    Hover(Option<(i32, i32)>), // where the pointer is in the window, if it is
+    Click(i32, i32, Sender<Option<Keystroke>>), // answered with a clicked binding's key
+    Scroll(i32),               // by pages, down if positive
     Cancel,
     Toggle,
 }
 
+// The [help] table of the configuration file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    layout: Layout,
+    anchor: Anchor,
+    follow: Follow,
+    scale: Option<f64>,      // otherwise from the monitor's DPI
+    translucent: bool,       // if a compositor is running
+    delay: f64,              // seconds before the window of a mode is shown
+    hide_after: Option<f64>, // seconds without a key press before it's hidden again
+    sticky: bool,            // whether the window toggled on stays shown until toggled off
+    theme: ThemeSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            layout: Default::default(),
+            anchor: Default::default(),
+            follow: Default::default(),
+            scale: None,
+            translucent: false,
+            delay: 1.0,
+            hide_after: None,
+            sticky: false,
+            theme: Default::default(),
+        }
+    }
+}
+
+impl Settings {
+    // Durations are checked when the configuration is loaded
+    pub fn validate(&self) -> Result<(), String> {
+        if config::duration(self.delay).is_none() {
+            return Err("Help delay must not be negative or more than a day".to_owned());
+        }
+        if let Some(None) = self.hide_after.map(config::duration) {
+            return Err("Help hide_after must not be negative or more than a day".to_owned());
+        }
+        self.theme.validate()
+    }
+
+    // For modes that don't have a delay of their own
+    pub fn delay(&self) -> Duration {
+        config::duration(self.delay).unwrap_or_default()
+    }
+
+    // The [help.theme] table applied over a theme, e.g. the one from resources
+    pub fn theme(&self, mut theme: Theme) -> Theme {
+        if let Err(e) = self.theme.apply(&mut theme) {
+            log::warn!("{}", e);
+        }
+        theme
+    }
+
+    fn hide_after(&self) -> Option<Duration> {
+        self.hide_after.and_then(config::duration)
+    }
+}
+
+// How the bindings are arranged
+#[derive(Debug, Default, Copy, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    #[default]
+    Columns, // as few columns as fit the height of the monitor
+    Bar, // as many columns as fit across the bottom of the monitor
+}
+
+// Where the window goes on its monitor
+#[derive(Debug, Default, Copy, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Anchor {
+    #[default]
+    Left,
+    Right,
+    Center,
+    Bottom,
+}
+
+// How the window's monitor is chosen
+#[derive(Debug, Default, Copy, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Follow {
+    #[default]
+    Focus, // the monitor with the focused window, or else the pointer
+    Pointer,
+}
+
 pub struct HelpWindow {
     window: xcb::Window,
+    colormap: Option<xcb::Colormap>, // only for a translucent window
     is_visible: bool,
+    is_sticky: bool, // toggled on, so it stays shown between modes
     width: u32,
     height: u32,
+    row_height: u32,
     header_column_widths: (u32, u32),         // title, keystrokes
     body_column_widths: (u32, u32, u32, u32), // modifiers, keystroke, arrow, title
-    groups: Vec<(Option<&'static str>, Vec<(Keystroke, &'static str)>)>,
-    system_bindings: BTreeMap<&'static str, Vec<Keystroke>>, // BTreeMap to retain sort order
+    columns: Vec<Vec<Row>>,                   // that the groups are flowed into
+    body_top: u32,                            // where the columns start
+    body_height: u32,                         // of the tallest column
+    visible_body_height: u32,                 // as much of it as fits the monitor
+    footer_height: u32,                       // room for the longest description, if any
+    scroll: u32,                              // how far down the columns are shown from
+    hovered: Option<Row>,
+    collapsed: HashSet<&'static str>, // groups that only show their name
+    settings: Settings,
+    theme: Theme,                         // the settings' theme over the resource theme
+    monitor: Option<connection::Monitor>, // chosen when the window is shown
+    scale: f64,                           // for the monitor's DPI
+    title: Option<&'static str>,
+    groups: Vec<(Option<&'static str>, Vec<Binding>)>,
+    system_bindings: BTreeMap<&'static str, Vec<KeySpec>>, // BTreeMap to retain sort order
+    prompt: Option<Prompt>,
+}
+
+// A line in a column of bindings, which is either the name of a group or one of
+// its bindings, by index into HelpWindow::groups
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Row {
+    Group(usize),
+    Binding(usize, usize),
 }
 
 impl HelpWindow {
     pub fn run(&mut self, rx: Receiver<HelpMessage>) {
         log::debug!("Help server started");
 
-        let mut is_armed = false;
+        // When the window is to be shown because a mode is waiting for a key, and
+        // when it's to be hidden again after showing itself
+        let mut show_at: Option<Instant> = None;
+        let mut hide_at: Option<Instant> = None;
         loop {
-            if !is_armed {
-                match rx.recv() {
-                    Ok(HelpMessage::Arm) => is_armed = true,
-                    Ok(HelpMessage::Disarm) => (),
-                    Ok(HelpMessage::Update(bindings)) => {
-                        self.update(bindings);
-                    }
-                    Ok(HelpMessage::Draw) => {
-                        self.draw();
+            let message = match show_at.into_iter().chain(hide_at).min() {
+                Some(deadline) => {
+                    rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match message {
+                Ok(HelpMessage::Arm(delay)) => show_at = Some(Instant::now() + delay),
+                Ok(HelpMessage::Disarm) => {
+                    show_at = None;
+                    // Keys being pressed keep the window up
+                    if hide_at.is_some() {
+                        hide_at = self
+                            .settings
+                            .hide_after()
+                            .map(|after| Instant::now() + after);
                     }
-                    Ok(HelpMessage::Cancel) => {
+                }
+                Ok(HelpMessage::Configure(settings)) => {
+                    self.configure(settings);
+                }
+                Ok(HelpMessage::Update(title, bindings)) => {
+                    self.update(title, bindings);
+                }
+                Ok(HelpMessage::Prompt(prompt)) => {
+                    hide_at = None;
+                    self.set_prompt(Some(prompt));
+                }
+                Ok(HelpMessage::Draw) => {
+                    self.draw();
+                }
+                Ok(HelpMessage::Reposition) => {
+                    self.reposition();
+                }
+                Ok(HelpMessage::Hover(position)) => {
+                    self.hover(position);
+                }
+                Ok(HelpMessage::Click(x, y, reply)) => {
+                    let _ = reply.send(self.click(x, y));
+                }
+                Ok(HelpMessage::Scroll(pages)) => {
+                    self.scroll_by(pages);
+                }
+                Ok(HelpMessage::Cancel) => {
+                    show_at = None;
+                    hide_at = None;
+                    if !self.is_sticky {
                         self.set_visible(false);
                     }
-                    Ok(HelpMessage::Toggle) => {
-                        self.set_visible(!self.is_visible);
+                    if self.prompt.is_some() {
+                        self.set_prompt(None);
                     }
-                    Err(_) => break,
                 }
-            } else {
-                is_armed = false;
-                match rx.recv_timeout(Duration::from_secs(1)) {
-                    Ok(HelpMessage::Arm) => is_armed = true,
-                    Ok(HelpMessage::Disarm) => (),
-                    Ok(HelpMessage::Update(bindings)) => {
-                        self.update(bindings);
-                    }
-                    Ok(HelpMessage::Draw) => {
-                        self.draw();
+                Ok(HelpMessage::Toggle) => {
+                    show_at = None;
+                    hide_at = None;
+                    let visible = !self.is_visible;
+                    self.is_sticky = visible && self.settings.sticky;
+                    self.set_visible(visible);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let now = Instant::now();
+                    if show_at.map_or(false, |at| at <= now) {
+                        show_at = None;
+                        if !self.is_visible {
+                            self.set_visible(true);
+                            hide_at = self.settings.hide_after().map(|after| now + after);
+                        }
                     }
-                    Ok(HelpMessage::Cancel) => {
+                    if hide_at.map_or(false, |at| at <= now) {
+                        hide_at = None;
                         self.set_visible(false);
                     }
-                    Ok(HelpMessage::Toggle) => {
-                        self.set_visible(!self.is_visible);
-                    }
-                    Err(RecvTimeoutError::Timeout) => {
-                        self.set_visible(true);
-                    }
-                    Err(_) => break,
                 }
+                Err(_) => break,
             }
         }
 
@@ -83,42 +245,34 @@ impl HelpWindow {
     }
 
     pub fn new() -> HelpWindow {
-        let connection = connection::connection();
-        let screen = connection.get_setup().roots().nth(0).unwrap();
-        let root = screen.root();
-        let root_visual = screen.root_visual();
-
-        let values = [
-            (xcb::CW_BACK_PIXEL, screen.white_pixel()),
-            (xcb::CW_EVENT_MASK, xcb::EVENT_MASK_EXPOSURE),
-            (xcb::CW_OVERRIDE_REDIRECT, 1),
-        ];
-
-        let window = connection.generate_id();
-        xcb::create_window(
-            &connection,
-            xcb::COPY_FROM_PARENT as u8,
-            window,
-            root,
-            -100,
-            -100,
-            1,
-            1,
-            1,
-            xcb::WINDOW_CLASS_INPUT_OUTPUT as u16,
-            root_visual,
-            &values,
-        );
+        let (window, colormap) = create_window(None);
 
         HelpWindow {
             window,
+            colormap,
             is_visible: false,
+            is_sticky: false,
             width: 0,
             height: 0,
+            row_height: 0,
             header_column_widths: Default::default(),
             body_column_widths: Default::default(),
+            columns: Default::default(),
+            body_top: 0,
+            body_height: 0,
+            visible_body_height: 0,
+            footer_height: 0,
+            scroll: 0,
+            hovered: None,
+            collapsed: Default::default(),
+            settings: Default::default(),
+            theme: Theme::from_resources(),
+            monitor: None,
+            scale: 1.0,
+            title: None,
             groups: Default::default(),
             system_bindings: Default::default(),
+            prompt: None,
         }
     }
 
@@ -126,21 +280,9 @@ impl HelpWindow {
         if self.is_visible != visible {
             let connection = connection::connection();
             if visible {
-                let root = connection.get_setup().roots().nth(0).unwrap().root();
-                let geometry = xcb::get_geometry(connection, root).get_reply().unwrap();
-                xcb::configure_window(
-                    connection,
-                    self.window,
-                    &[
-                        (xcb::CONFIG_WINDOW_X as u16, 0),
-                        (
-                            xcb::CONFIG_WINDOW_Y as u16,
-                            (geometry.height() as u32 - self.height) / 2,
-                        ),
-                        (xcb::CONFIG_WINDOW_WIDTH as u16, self.width),
-                        (xcb::CONFIG_WINDOW_HEIGHT as u16, self.height),
-                    ],
-                );
+                self.update_visual();
+                self.choose_monitor();
+                self.place();
                 xcb::map_window(connection, self.window);
             } else {
                 xcb::unmap_window(connection, self.window);
@@ -150,27 +292,286 @@ impl HelpWindow {
         }
     }
 
-    fn update(&mut self, bindings: Vec<Binding>) {
+    fn choose_monitor(&mut self) {
+        let position = match self.settings.follow {
+            Follow::Focus => connection::focused_window()
+                .and_then(connection::window_center)
+                .or_else(connection::pointer_position),
+            Follow::Pointer => connection::pointer_position(),
+        };
+        let monitor = connection::monitor_at(position);
+        let is_new_monitor = self.monitor != Some(monitor);
+        self.monitor = Some(monitor);
+
+        // Relative to 96 DPI, in quarters so that lines stay reasonably sharp
+        let scale = self.settings.scale.unwrap_or_else(|| {
+            let dpi = monitor.dpi.or_else(connection::xft_dpi).unwrap_or(96.0);
+            (dpi / 96.0 * 4.0).round().max(4.0) / 4.0
+        });
+        if scale != self.scale {
+            log::debug!("Scale help window by {}", scale);
+            self.scale = scale;
+            self.measure();
+        } else if is_new_monitor {
+            // The bindings are flowed to fit the monitor
+            self.measure();
+        }
+    }
+
+    // Move and resize the window to suit its contents and anchor on its monitor
+    fn place(&self) {
+        if let Some(monitor) = self.monitor {
+            let (width, height) = self.physical_size();
+            let (width, height) = (width as i32, height as i32);
+            let center_x = monitor.x + (monitor.width as i32 - width) / 2;
+            let center_y = monitor.y + (monitor.height as i32 - height) / 2;
+            let bottom_y = monitor.y + monitor.height as i32 - height;
+            let (x, y) = match (self.settings.layout, self.settings.anchor) {
+                (Layout::Bar, _) => (center_x, bottom_y),
+                (_, Anchor::Left) => (monitor.x, center_y),
+                (_, Anchor::Right) => (monitor.x + monitor.width as i32 - width, center_y),
+                (_, Anchor::Center) => (center_x, center_y),
+                (_, Anchor::Bottom) => (center_x, bottom_y),
+            };
+            xcb::configure_window(
+                connection::connection(),
+                self.window,
+                &[
+                    (xcb::CONFIG_WINDOW_X as u16, x as u32),
+                    (xcb::CONFIG_WINDOW_Y as u16, y as u32),
+                    (xcb::CONFIG_WINDOW_WIDTH as u16, width as u32),
+                    (xcb::CONFIG_WINDOW_HEIGHT as u16, height as u32),
+                ],
+            );
+        }
+    }
+
+    // The width and height are measured before scaling
+    fn physical_size(&self) -> (u32, u32) {
+        (
+            (self.width as f64 * self.scale).ceil() as u32,
+            (self.height as f64 * self.scale).ceil() as u32,
+        )
+    }
+
+    // The monitors have changed, or the settings for placing the window
+    fn reposition(&mut self) {
+        if self.is_visible {
+            self.choose_monitor();
+            self.place();
+            connection::connection().flush();
+        }
+    }
+
+    // Resources are read again too, in case they've been changed with xrdb
+    fn configure(&mut self, settings: Settings) {
+        self.theme = settings.theme(Theme::from_resources());
+        self.settings = settings;
+        self.update_visual();
+        if self.colormap.is_none() {
+            xcb::change_window_attributes(
+                connection::connection(),
+                self.window,
+                &[(xcb::CW_BACK_PIXEL, self.theme.background.pixel())],
+            );
+        }
+        self.layout();
+        self.reposition();
+    }
+
+    // A translucent window needs a 32 bit visual, and only a compositor can blend
+    // it with what's behind, so the window is made again if either changes
+    fn update_visual(&mut self) {
+        let argb_visual = if self.settings.translucent && connection::compositor_is_running() {
+            connection::argb_visual()
+        } else {
+            None
+        };
+        if argb_visual.is_some() != self.colormap.is_some() {
+            log::debug!("Make help window translucent: {}", argb_visual.is_some());
+            self.destroy_window();
+            let (window, colormap) = create_window(argb_visual);
+            self.window = window;
+            self.colormap = colormap;
+            if self.is_visible {
+                self.place();
+                xcb::map_window(connection::connection(), self.window);
+            }
+        }
+    }
+
+    fn destroy_window(&self) {
+        let connection = connection::connection();
+        xcb::destroy_window(connection, self.window);
+        if let Some(colormap) = self.colormap {
+            xcb::free_colormap(connection, colormap);
+        }
+    }
+
+    // Backgrounds are only translucent when the window can be
+    fn opacity(&self) -> f64 {
+        match self.colormap {
+            Some(_) => self.theme.opacity.max(0.0).min(1.0),
+            None => 1.0,
+        }
+    }
+
+    fn update(&mut self, title: Option<&'static str>, bindings: Vec<Binding>) {
+        self.title = title;
         self.set_bindings(bindings);
+        self.scroll = 0;
+        self.hovered = None;
+        self.layout();
+    }
+
+    // The row under a point in the window, in pixels
+    fn row_at(&self, x: i32, y: i32) -> Option<Row> {
+        if self.prompt.is_some() {
+            return None;
+        }
+        let theme = &self.theme;
+        let (p, b) = (theme.padding as f64, theme.border_width as f64);
+        let (x, y) = (x as f64 / self.scale - b, y as f64 / self.scale - b);
+        let top = self.body_top as f64;
+        if y < top || y >= top + self.visible_body_height as f64 {
+            return None;
+        }
+
+        // Columns reach halfway into the padding on either side
+        let index = ((x - p / 2.0) / (self.column_width() as f64 + p)).floor();
+        if index < 0.0 {
+            return None;
+        }
+        let mut row_top = top - self.scroll as f64;
+        for &row in self.columns.get(index as usize)? {
+            row_top += self.height_of(row) as f64;
+            if y < row_top {
+                return Some(row);
+            }
+        }
+        None
+    }
+
+    fn hover(&mut self, position: Option<(i32, i32)>) {
+        let hovered = position.and_then(|(x, y)| self.row_at(x, y));
+        if hovered != self.hovered {
+            self.hovered = hovered;
+            if self.is_visible {
+                self.draw();
+            }
+        }
+    }
+
+    // Clicking a binding runs it, by returning its key, and clicking the name of a
+    // group collapses or expands it
+    fn click(&mut self, x: i32, y: i32) -> Option<Keystroke> {
+        match self.row_at(x, y)? {
+            Row::Group(group) => {
+                if let Some(name) = self.groups[group].0 {
+                    if !self.collapsed.remove(name) {
+                        self.collapsed.insert(name);
+                    }
+                    self.layout();
+                    self.hovered = self.row_at(x, y);
+                    self.draw();
+                }
+                None
+            }
+            Row::Binding(group, binding) => {
+                self.groups[group].1[binding].keystrokes().first().copied()
+            }
+        }
+    }
+
+    // Columns that are too tall for the monitor scroll a page at a time
+    fn scroll_by(&mut self, pages: i32) {
+        let page = self
+            .visible_body_height
+            .saturating_sub(self.row_height)
+            .max(self.row_height) as i32;
+        let most = self.body_height.saturating_sub(self.visible_body_height) as i32;
+        let scroll = (self.scroll as i32 + pages * page).max(0).min(most) as u32;
+        if scroll != self.scroll {
+            self.scroll = scroll;
+            self.hovered = None;
+            self.draw();
+        }
+    }
+
+    // The width of a column of bindings, unscaled
+    fn column_width(&self) -> u32 {
+        let (w1, w2, w3, w4) = self.body_column_widths;
+        w1 + w2 + self.theme.padding + w3 + self.theme.padding + w4
+    }
+
+    // The height of a row in a column of bindings, unscaled
+    fn height_of(&self, row: Row) -> u32 {
+        let (r, g) = (self.row_height, self.theme.group_spacing);
+        match row {
+            Row::Group(_) => g + r + g / 4 + g / 2,
+            Row::Binding(_, _) => r,
+        }
+    }
+
+    // Showing a prompt makes the window visible, and removing it reverts to the bindings
+    fn set_prompt(&mut self, prompt: Option<Prompt>) {
+        let is_prompt = prompt.is_some();
+        self.prompt = prompt;
+        self.layout();
+        if is_prompt {
+            self.set_visible(true);
+        }
+    }
+
+    fn layout(&mut self) {
+        self.measure();
+
+        let connection = connection::connection();
+        if let Ok(attributes) = xcb::get_window_attributes(connection, self.window).get_reply() {
+            if attributes.map_state() == xcb::MAP_STATE_VIEWABLE as u8 {
+                self.place();
+                connection.flush();
+                self.draw();
+            }
+        }
+    }
+
+    fn measure(&mut self) {
+        if self.prompt.is_some() {
+            self.layout_prompt();
+        } else {
+            self.layout_bindings();
+        }
+    }
 
+    fn layout_bindings(&mut self) {
         if let Ok(surface) = connection::get_cairo_surface(self.window) {
             let cairo_context = cairo::Context::new(&surface);
+            cairo_context.scale(self.scale, self.scale);
             if let Some(layout) = pangocairo::functions::create_layout(&cairo_context) {
-                let font_description =
-                    // pango::FontDescription::from_string("Operator Mono SSm Light 11px");
-                    pango::FontDescription::from_string("Noto Sans 11px");
-                let key_font_description =
-                    pango::FontDescription::from_string("Noto Sans Mono 11px");
+                let theme = &self.theme;
+                let font_description = pango::FontDescription::from_string(&theme.font);
+                let key_font_description = pango::FontDescription::from_string(&theme.key_font);
                 let symbol_font_description =
-                    pango::FontDescription::from_string("Lucida Grande 11px");
+                    pango::FontDescription::from_string(&theme.symbol_font);
+
+                self.row_height = row_height(&layout, &[&font_description, &key_font_description]);
+                let (p, r, g) = (theme.padding, self.row_height, theme.group_spacing);
 
                 self.height = 0;
                 self.width = 0;
 
+                if let Some(title) = self.title {
+                    layout.set_font_description(&font_description);
+                    layout.set_text(title);
+                    self.width = self.width.max(p + layout.get_pixel_size().0 as u32 + p);
+                    self.height += p + r + p;
+                }
+
                 if self.system_bindings.is_empty() {
                     self.header_column_widths = (0, 0);
                 } else {
-                    self.height += 10;
+                    self.height += p;
 
                     let mut width_1: u32 = 0;
                     let mut width_2: u32 = 0;
@@ -200,34 +601,50 @@ impl HelpWindow {
                         }
                         width_2 = width_2.max(w);
 
-                        self.height += 14;
+                        self.height += r;
                     }
 
                     self.header_column_widths = (width_1, width_2);
-                    self.width = self.width.max(10 + width_1 + width_2 + 10);
+                    self.width = self.width.max(p + width_1 + width_2 + p);
 
-                    self.height += 10;
+                    self.height += p;
                 }
 
                 if self.groups.is_empty() {
                     self.body_column_widths = (0, 0, 0, 0);
+                    self.columns = vec![];
+                    self.body_height = 0;
+                    self.visible_body_height = 0;
+                    self.footer_height = 0;
                 } else {
-                    self.height += 10;
+                    self.height += p;
+                    self.body_top = self.height;
 
                     let mut width_1: u32 = 0;
                     let mut width_2: u32 = 0;
                     layout.set_font_description(&font_description);
                     layout.set_text("\u{2794}");
-                    let width_3 = layout.get_pixel_size().0 as u32;
+                    let mut width_3 = layout.get_pixel_size().0 as u32;
+                    layout.set_text("\u{21bb}");
+                    width_3 = width_3.max(layout.get_pixel_size().0 as u32);
                     let mut width_4: u32 = 0;
 
+                    let mut groups = vec![];
                     for (group, group_bindings) in &self.groups {
-                        if let Some(_) = group {
-                            self.height += 8 + 14 + 2 + 4;
-                        }
-
-                        for (keystroke, label) in group_bindings {
-                            let (w1, w2) = keystroke.process_help(
+                        let header_height = match group {
+                            Some(_) => g + r + g / 4 + g / 2,
+                            None => 0,
+                        };
+                        // Collapsed groups still count towards the widths, so that the
+                        // columns don't jump around
+                        let count = match group {
+                            Some(name) if self.collapsed.contains(name) => 0,
+                            _ => group_bindings.len(),
+                        };
+                        groups.push((header_height, count));
+
+                        for binding in group_bindings {
+                            let (w1, w2) = binding.key().process_help(
                                 &cairo_context,
                                 &key_font_description,
                                 &symbol_font_description,
@@ -235,98 +652,292 @@ impl HelpWindow {
                             );
                             width_1 = width_1.max(w1);
                             width_2 = width_2.max(w2);
-                            layout.set_text(label);
+                            layout.set_text(binding.label());
                             width_4 = width_4.max(layout.get_pixel_size().0 as u32);
-
-                            self.height += 14;
                         }
                     }
                     self.body_column_widths = (width_1, width_2, width_3, width_4);
-                    self.width = self
-                        .width
-                        .max(10 + width_1 + width_2 + 10 + width_3 + 10 + width_4 + 10);
+                    let column_width = width_1 + width_2 + p + width_3 + p + width_4;
+
+                    // The room that the monitor leaves for the columns, unscaled
+                    let b = theme.border_width;
+                    let (room_width, room_height) = match self.monitor {
+                        Some(monitor) => (
+                            (monitor.width as f64 / self.scale).floor() as u32,
+                            ((monitor.height as f64 / self.scale).floor() as u32)
+                                .saturating_sub(b + self.height + p + b),
+                        ),
+                        None => (0, std::u32::MAX),
+                    };
+                    let (columns, height) = match self.settings.layout {
+                        Layout::Columns => {
+                            let count = flow(&groups, r, room_height).0.len();
+                            balance(&groups, r, count)
+                        }
+                        Layout::Bar => {
+                            let room = room_width.saturating_sub(b + p + b);
+                            let count = (room / (column_width + p).max(1)).max(1) as usize;
+                            balance(&groups, r, count)
+                        }
+                    };
+                    let count = columns.len() as u32;
+                    self.columns = columns;
 
-                    self.height += 10;
+                    self.width = self.width.max(p + count * (column_width + p));
+                    if let Layout::Bar = self.settings.layout {
+                        self.width = self.width.max(room_width.saturating_sub(b + b));
+                    }
+
+                    // The description of the binding under the pointer goes below
+                    let descriptions = self
+                        .groups
+                        .iter()
+                        .flat_map(|(_, group_bindings)| group_bindings)
+                        .filter_map(Binding::description);
+                    layout.set_font_description(&font_description);
+                    self.footer_height =
+                        footer_height(&layout, descriptions, self.width.saturating_sub(2 * p), p);
+
+                    // Whatever doesn't fit is scrolled to
+                    self.body_height = height;
+                    self.visible_body_height =
+                        height.min(room_height.saturating_sub(self.footer_height));
+                    self.scroll = self.scroll.min(self.body_height - self.visible_body_height);
+                    self.height += self.visible_body_height + p + self.footer_height;
                 }
 
+                self.width += 2 * theme.border_width;
+                self.height += 2 * theme.border_width;
 
                 log::debug!("Resize help window to {} x {}", self.width, self.height);
             }
         }
+    }
 
-        let connection = connection::connection();
-        if let Ok(attributes) = xcb::get_window_attributes(connection, self.window).get_reply() {
-            if attributes.map_state() == xcb::MAP_STATE_VIEWABLE as u8 {
-                let root = connection.get_setup().roots().nth(0).unwrap().root();
-                let geometry = xcb::get_geometry(connection, root).get_reply().unwrap();
-                xcb::configure_window(
-                    connection,
-                    self.window,
-                    &[
-                        (xcb::CONFIG_WINDOW_X as u16, 0),
-                        (
-                            xcb::CONFIG_WINDOW_Y as u16,
-                            (geometry.height() as u32 - self.height) / 2,
-                        ),
-                        (xcb::CONFIG_WINDOW_WIDTH as u16, self.width),
-                        (xcb::CONFIG_WINDOW_HEIGHT as u16, self.height),
-                    ],
-                );
-                connection.flush();
-                self.draw();
+    fn layout_prompt(&mut self) {
+        let prompt = match &self.prompt {
+            Some(prompt) => prompt,
+            None => return,
+        };
+
+        if let Ok(surface) = connection::get_cairo_surface(self.window) {
+            let cairo_context = cairo::Context::new(&surface);
+            cairo_context.scale(self.scale, self.scale);
+            if let Some(layout) = pangocairo::functions::create_layout(&cairo_context) {
+                let theme = &self.theme;
+                let font_description = pango::FontDescription::from_string(&theme.font);
+                let key_font_description = pango::FontDescription::from_string(&theme.key_font);
+                let symbol_font_description =
+                    pango::FontDescription::from_string(&theme.symbol_font);
+                self.row_height = row_height(&layout, &[&font_description, &key_font_description]);
+                let (p, r, b) = (theme.padding, self.row_height, theme.border_width);
+
+                layout.set_font_description(&font_description);
+                layout.set_text(&format!("{}: {}\u{258f}", prompt.title(), prompt.text()));
+                let mut width = layout.get_pixel_size().0 as u32;
+                for m in prompt.matches().iter().take(PROMPT_ROWS) {
+                    layout.set_text(prompt.choice(m));
+                    let mut w = layout.get_pixel_size().0 as u32;
+                    if let Some(keys) = prompt.keys(m) {
+                        w += p + process_keys_help(
+                            keys,
+                            &cairo_context,
+                            &font_description,
+                            &key_font_description,
+                            &symbol_font_description,
+                            false,
+                        );
+                    }
+                    width = width.max(w);
+                }
+
+                // Don't let the width jump around too much while typing
+                self.width = (b + p + width.max(400).min(800) + p + b)
+                    .max(self.width.min(b + p + 800 + p + b));
+                layout.set_font_description(&font_description);
+                self.footer_height =
+                    footer_height(&layout, prompt.descriptions(), self.width - 2 * (b + p), p);
+                self.height =
+                    b + (p + r + p) + (p + PROMPT_ROWS as u32 * r + p) + self.footer_height + b;
             }
         }
     }
 
     fn draw(&self) {
+        if self.prompt.is_some() {
+            self.draw_prompt();
+        } else {
+            self.draw_bindings();
+        }
+    }
+
+    fn draw_prompt(&self) {
+        let prompt = match &self.prompt {
+            Some(prompt) => prompt,
+            None => return,
+        };
+
         if let Ok(surface) = connection::get_cairo_surface(self.window) {
             let cairo_context = cairo::Context::new(&surface);
+            cairo_context.scale(self.scale, self.scale);
             if let Some(layout) = pangocairo::functions::create_layout(&cairo_context) {
-                let font_description =
-                    // pango::FontDescription::from_string("Operator Mono SSm Light 11px");
-                    pango::FontDescription::from_string("Noto Sans 11px");
-                let key_font_description =
-                    pango::FontDescription::from_string("Noto Sans Mono 11px");
+                let theme = &self.theme;
+                let font_description = pango::FontDescription::from_string(&theme.font);
+                layout.set_font_description(&font_description);
+                let key_font_description = pango::FontDescription::from_string(&theme.key_font);
                 let symbol_font_description =
-                    pango::FontDescription::from_string("Lucida Grande 11px");
+                    pango::FontDescription::from_string(&theme.symbol_font);
+                let (p, r) = (theme.padding as f64, self.row_height as f64);
 
-                layout.set_font_description(&font_description);
+                let width = self.draw_background(&cairo_context);
 
-                cairo_context.set_source_rgb(1.0, 1.0, 0.95);
-                cairo_context.move_to(0.0, 0.0);
-                cairo_context.line_to(self.width as f64, 0.0);
-                cairo_context.line_to(self.width as f64, self.height as f64);
-                cairo_context.line_to(0.0, self.height as f64);
-                cairo_context.close_path();
+                theme
+                    .header_background
+                    .set_source_with_alpha(&cairo_context, self.opacity());
+                cairo_context.rectangle(0.0, 0.0, width, p + r + p);
                 cairo_context.fill();
 
+                theme.separator.set_source(&cairo_context);
+                cairo_context.move_to(0.0, p + r + p - 0.5);
+                cairo_context.rel_line_to(width, 0.0);
+                cairo_context.set_line_width(1.0);
+                cairo_context.stroke();
+
+                theme.foreground.set_source(&cairo_context);
+                cairo_context.move_to(p, p);
+                layout.set_text(&format!("{}: {}\u{258f}", prompt.title(), prompt.text()));
+                pangocairo::functions::show_layout(&cairo_context, &layout);
+
+                let mut y = p + r + p + p;
+
+                // Scroll so that the selection is always visible
+                let first = (prompt.selected() + 1).saturating_sub(PROMPT_ROWS);
+                for (index, m) in prompt
+                    .matches()
+                    .iter()
+                    .enumerate()
+                    .skip(first)
+                    .take(PROMPT_ROWS)
+                {
+                    if index == prompt.selected() {
+                        theme.separator.set_source(&cairo_context);
+                        cairo_context.rectangle(0.0, y, width, r);
+                        cairo_context.fill();
+                    }
+
+                    theme.foreground.set_source(&cairo_context);
+                    cairo_context.move_to(p, y);
+                    layout.set_markup(&highlight_markup(
+                        prompt.choice(m),
+                        m.positions(),
+                        &theme.accent.to_hex(),
+                    ));
+                    pangocairo::functions::show_layout(&cairo_context, &layout);
+
+                    // Keys are right aligned
+                    if let Some(keys) = prompt.keys(m) {
+                        let keys_width = process_keys_help(
+                            keys,
+                            &cairo_context,
+                            &font_description,
+                            &key_font_description,
+                            &symbol_font_description,
+                            false,
+                        );
+                        cairo_context.move_to(width - p - keys_width as f64, y);
+                        process_keys_help(
+                            keys,
+                            &cairo_context,
+                            &font_description,
+                            &key_font_description,
+                            &symbol_font_description,
+                            true,
+                        );
+                    }
+
+                    y += r;
+                }
+
+                if self.footer_height > 0 {
+                    let selected = prompt.matches().get(prompt.selected());
+                    self.draw_footer(
+                        &cairo_context,
+                        &layout,
+                        selected.and_then(|m| prompt.description(m)),
+                        p + r + p + p + PROMPT_ROWS as f64 * r + p,
+                        width,
+                    );
+                }
+
+                self.draw_border(&cairo_context);
+            }
+            connection::connection().flush();
+        }
+    }
+
+    fn draw_bindings(&self) {
+        if let Ok(surface) = connection::get_cairo_surface(self.window) {
+            let cairo_context = cairo::Context::new(&surface);
+            cairo_context.scale(self.scale, self.scale);
+            if let Some(layout) = pangocairo::functions::create_layout(&cairo_context) {
+                let theme = &self.theme;
+                let font_description = pango::FontDescription::from_string(&theme.font);
+                let key_font_description = pango::FontDescription::from_string(&theme.key_font);
+                let symbol_font_description =
+                    pango::FontDescription::from_string(&theme.symbol_font);
+                let (p, r, g) = (
+                    theme.padding as f64,
+                    self.row_height as f64,
+                    theme.group_spacing as f64,
+                );
+
+                layout.set_font_description(&font_description);
+
+                let width = self.draw_background(&cairo_context);
 
                 let mut y = 0.0;
 
+                // The keystrokes so far, when in the middle of a key sequence
+                if let Some(title) = self.title {
+                    theme
+                        .header_background
+                        .set_source_with_alpha(&cairo_context, self.opacity());
+                    cairo_context.rectangle(0.0, 0.0, width, p + r + p);
+                    cairo_context.fill();
+
+                    theme.separator.set_source(&cairo_context);
+                    cairo_context.move_to(0.0, p + r + p - 0.5);
+                    cairo_context.rel_line_to(width, 0.0);
+                    cairo_context.set_line_width(1.0);
+                    cairo_context.stroke();
+
+                    theme.accent.set_source(&cairo_context);
+                    cairo_context.move_to(p, p);
+                    layout.set_text(title);
+                    pangocairo::functions::show_layout(&cairo_context, &layout);
+
+                    y += p + r + p;
+                }
+
                 if !self.system_bindings.is_empty() {
-                    cairo_context.set_source_rgb(0.9, 1.0, 0.9);
-                    cairo_context.move_to(0.0, 0.0);
-                    cairo_context.rel_line_to(self.width as f64, 0.0);
-                    cairo_context
-                        .rel_line_to(0.0, (10 + self.system_bindings.len() * 14 + 10) as f64);
-                    cairo_context.rel_line_to(0.0 - self.width as f64, 0.0);
-                    cairo_context.close_path();
+                    let band_height = p + self.system_bindings.len() as f64 * r + p;
+                    theme
+                        .header_background
+                        .set_source_with_alpha(&cairo_context, self.opacity());
+                    cairo_context.rectangle(0.0, y, width, band_height);
                     cairo_context.fill();
 
-                    cairo_context.set_source_rgb(0.8, 0.9, 0.8);
-                    cairo_context.move_to(
-                        0.0,
-                        (10 + self.system_bindings.len() * 14 + 10) as f64 - 0.5,
-                    );
-                    cairo_context.rel_line_to(self.width as f64, 0.0);
+                    theme.separator.set_source(&cairo_context);
+                    cairo_context.move_to(0.0, y + band_height - 0.5);
+                    cairo_context.rel_line_to(width, 0.0);
                     cairo_context.set_line_width(1.0);
                     cairo_context.stroke();
 
-                    cairo_context.set_source_rgb(0.0, 0.0, 0.0);
+                    theme.foreground.set_source(&cairo_context);
 
-                    y += 10.0;
+                    y += p;
 
-                    let x_column_1 = 10.0;
+                    let x_column_1 = p;
                     let x_column_2 = x_column_1 + self.header_column_widths.0 as f64;
                     for (label, keystrokes) in &self.system_bindings {
                         let mut x = x_column_1;
@@ -367,73 +978,210 @@ impl HelpWindow {
                             }
                         }
 
-                        y += 14.0;
+                        y += r;
                     }
-
-                    y += 10.0;
                 }
 
-                if !self.groups.is_empty() {
-                    y += 10.0;
+                // Columns that don't fit are clipped and scrolled
+                let top = self.body_top as f64;
+                cairo_context.save();
+                cairo_context.rectangle(0.0, top, width, self.visible_body_height as f64);
+                cairo_context.clip();
 
-                    let x_column_1 = 10.0;
+                let column_width = self.column_width() as f64;
+                for (index, column) in self.columns.iter().enumerate() {
+                    let mut y = top - self.scroll as f64;
+
+                    let x_column_1 = p + index as f64 * (column_width + p);
                     let x_column_2 = x_column_1 + self.body_column_widths.0 as f64;
-                    let x_column_3 = x_column_2 + self.body_column_widths.1 as f64 + 10.0;
-                    let x_column_4 = x_column_3 + self.body_column_widths.2 as f64 + 10.0;
+                    let x_column_3 = x_column_2 + self.body_column_widths.1 as f64 + p;
+                    let x_column_4 = x_column_3 + self.body_column_widths.2 as f64 + p;
                     let x_right = x_column_4 + self.body_column_widths.3 as f64;
-                    for (group, group_bindings) in &self.groups {
-                        if let Some(group_name) = group {
-                            y += 8.0;
-                            cairo_context.set_source_rgb(0.0, 0.5, 0.0);
-                            cairo_context.move_to(x_column_1, y);
-                            layout.set_text(group_name);
-                            pangocairo::functions::show_layout(&cairo_context, &layout);
-                            y += 14.0;
-
-                            y += 2.0;
-                            cairo_context.set_source_rgb(0.7, 0.85, 0.7);
-                            cairo_context.move_to(x_column_1, y + 0.5);
-                            cairo_context.line_to(x_right, y + 0.5);
-                            cairo_context.set_line_width(1.0);
-                            cairo_context.stroke();
-                            y += 4.0;
+                    for row in column {
+                        if Some(*row) == self.hovered {
+                            theme.separator.set_source(&cairo_context);
+                            cairo_context.rectangle(
+                                x_column_1 - p / 2.0,
+                                y,
+                                column_width + p,
+                                self.height_of(*row) as f64,
+                            );
+                            cairo_context.fill();
                         }
 
-                        for (keystroke, label) in group_bindings {
-                            cairo_context.set_source_rgb(0.0, 0.0, 0.0);
+                        match *row {
+                            Row::Group(group) => {
+                                y += g;
+                                theme.group_foreground.set_source(&cairo_context);
+                                cairo_context.move_to(x_column_1, y);
+                                let name = self.groups[group].0.unwrap_or_default();
+                                if self.collapsed.contains(name) {
+                                    layout.set_text(&format!("{} \u{25b8}", name));
+                                } else {
+                                    layout.set_text(name);
+                                }
+                                pangocairo::functions::show_layout(&cairo_context, &layout);
+                                y += r;
+
+                                // The rule is a quarter of the spacing below the name,
+                                // and half of it above the first binding
+                                y += (g / 4.0).floor();
+                                theme.group_rule.set_source(&cairo_context);
+                                cairo_context.move_to(x_column_1, y + 0.5);
+                                cairo_context.line_to(x_right, y + 0.5);
+                                cairo_context.set_line_width(1.0);
+                                cairo_context.stroke();
+                                y += (g / 2.0).floor();
+                            }
 
-                            cairo_context.move_to(x_column_2, y);
-                            keystroke.process_help(
-                                &cairo_context,
-                                &key_font_description,
-                                &symbol_font_description,
-                                true,
-                            );
+                            Row::Binding(group, binding) => {
+                                let binding = &self.groups[group].1[binding];
+                                theme.foreground.set_source(&cairo_context);
 
-                            cairo_context.move_to(x_column_4, y);
-                            layout.set_text(label);
-                            pangocairo::functions::show_layout(&cairo_context, &layout);
+                                cairo_context.move_to(x_column_2, y);
+                                binding.key().process_help(
+                                    &cairo_context,
+                                    &key_font_description,
+                                    &symbol_font_description,
+                                    true,
+                                );
 
-                            cairo_context.set_source_rgb(0.7, 0.7, 0.7);
+                                cairo_context.move_to(x_column_4, y);
+                                layout.set_text(binding.label());
+                                pangocairo::functions::show_layout(&cairo_context, &layout);
 
-                            cairo_context.move_to(x_column_3, y);
-                            layout.set_text("\u{2794}");
-                            pangocairo::functions::show_layout(&cairo_context, &layout);
+                                // Hydra heads keep the mode open, so they get a loop
+                                // instead of an arrow
+                                cairo_context.move_to(x_column_3, y);
+                                if let Action::Call(_) = binding.action() {
+                                    theme.accent.set_source(&cairo_context);
+                                    layout.set_text("\u{21bb}");
+                                } else {
+                                    theme.arrow.set_source(&cairo_context);
+                                    layout.set_text("\u{2794}");
+                                }
+                                pangocairo::functions::show_layout(&cairo_context, &layout);
 
-                            y += 14.0;
+                                y += r;
+                            }
                         }
                     }
+                }
+                cairo_context.restore();
 
+                if self.footer_height > 0 {
+                    let description = match self.hovered {
+                        Some(Row::Binding(group, binding)) => {
+                            self.groups[group].1[binding].description()
+                        }
+                        _ => None,
+                    };
+                    layout.set_font_description(&font_description);
+                    self.draw_footer(
+                        &cairo_context,
+                        &layout,
+                        description,
+                        top + self.visible_body_height as f64 + p,
+                        width,
+                    );
                 }
+
+                self.draw_border(&cairo_context);
             }
             connection::connection().flush();
         }
     }
 
+    // A rule, and below it the description wrapped to the width
+    fn draw_footer(
+        &self,
+        cairo_context: &cairo::Context,
+        layout: &pango::Layout,
+        description: Option<&str>,
+        top: f64,
+        width: f64,
+    ) {
+        let theme = &self.theme;
+        let p = theme.padding as f64;
+
+        theme.separator.set_source(cairo_context);
+        cairo_context.move_to(0.0, top + 0.5);
+        cairo_context.rel_line_to(width, 0.0);
+        cairo_context.set_line_width(1.0);
+        cairo_context.stroke();
+
+        if let Some(description) = description {
+            theme.foreground.set_source(cairo_context);
+            cairo_context.move_to(p, top + p);
+            layout.set_width(((width - 2.0 * p) * pango::SCALE as f64) as i32);
+            layout.set_wrap(pango::WrapMode::WordChar);
+            layout.set_text(description);
+            pangocairo::functions::show_layout(cairo_context, layout);
+            layout.set_width(-1);
+        }
+    }
+
+    // Fill the background and clip to it, then move the origin inside the border
+    // until draw_border. Returns the width inside the border.
+    fn draw_background(&self, cairo_context: &cairo::Context) -> f64 {
+        let theme = &self.theme;
+        if self.colormap.is_some() {
+            // Leave the corners outside the radius transparent
+            cairo_context.save();
+            cairo_context.set_operator(cairo::Operator::Source);
+            cairo_context.set_source_rgba(0.0, 0.0, 0.0, 0.0);
+            cairo_context.paint();
+            cairo_context.restore();
+        }
+        self.frame_path(cairo_context);
+        theme
+            .background
+            .set_source_with_alpha(cairo_context, self.opacity());
+        cairo_context.fill_preserve();
+        cairo_context.save();
+        cairo_context.clip();
+
+        let border = theme.border_width as f64;
+        cairo_context.translate(border, border);
+        self.width as f64 - 2.0 * border
+    }
+
+    fn draw_border(&self, cairo_context: &cairo::Context) {
+        let theme = &self.theme;
+        cairo_context.restore();
+        if theme.border_width > 0 {
+            self.frame_path(cairo_context);
+            theme.border_color.set_source(cairo_context);
+            cairo_context.set_line_width(theme.border_width as f64);
+            cairo_context.stroke();
+        }
+    }
+
+    // The outline of the window with rounded corners, along the middle of the border
+    fn frame_path(&self, cairo_context: &cairo::Context) {
+        use std::f64::consts::PI;
+        let theme = &self.theme;
+        let inset = theme.border_width as f64 / 2.0;
+        let (left, top) = (inset, inset);
+        let (right, bottom) = (self.width as f64 - inset, self.height as f64 - inset);
+        let radius = theme
+            .corner_radius
+            .min((right - left) / 2.0)
+            .min((bottom - top) / 2.0)
+            .max(0.0);
+
+        cairo_context.new_path();
+        cairo_context.arc(right - radius, top + radius, radius, -PI / 2.0, 0.0);
+        cairo_context.arc(right - radius, bottom - radius, radius, 0.0, PI / 2.0);
+        cairo_context.arc(left + radius, bottom - radius, radius, PI / 2.0, PI);
+        cairo_context.arc(left + radius, top + radius, radius, PI, PI * 1.5);
+        cairo_context.close_path();
+    }
+
     fn set_bindings(&mut self, bindings: Vec<Binding>) {
         let (mut system_bindings, mut groups): (Vec<Binding>, Vec<Binding>) =
             bindings.into_iter().partition(|b| match b.action() {
-                Action::Cancel | Action::ToggleHelp => true,
+                Action::Cancel | Action::ToggleHelp | Action::SearchHelp | Action::Palette => true,
                 _ => false,
             });
 
@@ -442,7 +1190,12 @@ impl HelpWindow {
             .iter()
             .group_by(|b| b.label())
             .into_iter()
-            .map(|(label, bindings)| (label, bindings.into_iter().map(|b| b.keystroke()).collect()))
+            .map(|(label, bindings)| {
+                (
+                    label,
+                    bindings.into_iter().map(|b| b.key().clone()).collect(),
+                )
+            })
             .collect();
 
         groups.sort_by_key(|b| b.group());
@@ -450,26 +1203,242 @@ impl HelpWindow {
             .iter()
             .group_by(|b| b.group())
             .into_iter()
-            .map(|(group, bindings)| {
-                (
-                    group,
-                    bindings
-                        .into_iter()
-                        .map(|b| (b.keystroke(), b.label()))
-                        .collect(),
-                )
-            })
+            .map(|(group, bindings)| (group, bindings.into_iter().cloned().collect()))
             .collect();
     }
 }
 
+// Flow groups into columns no taller than the height, moving a group that doesn't
+// fit to the next column unless it's too tall for any column. Groups are given as
+// the height of their header and their number of bindings. Returns the columns and
+// the height of the tallest.
+fn flow(groups: &[(u32, usize)], row_height: u32, height: u32) -> (Vec<Vec<Row>>, u32) {
+    let mut columns: Vec<Vec<Row>> = vec![vec![]];
+    let (mut y, mut tallest) = (0, 0);
+    // Whether something of the height needs a new column below y
+    let is_full = |y: u32, needed: u32| y > 0 && y + needed > height;
+    for (group, &(header_height, count)) in groups.iter().enumerate() {
+        let group_height = header_height + count as u32 * row_height;
+        if group_height <= height && is_full(y, group_height) {
+            columns.push(vec![]);
+            y = 0;
+        }
+        // Keep the name with the first binding
+        if header_height > 0 {
+            if is_full(y, header_height + row_height) {
+                columns.push(vec![]);
+                y = 0;
+            }
+            columns.last_mut().unwrap().push(Row::Group(group));
+            y += header_height;
+            tallest = tallest.max(y);
+        }
+        for binding in 0..count {
+            if is_full(y, row_height) {
+                columns.push(vec![]);
+                y = 0;
+            }
+            columns
+                .last_mut()
+                .unwrap()
+                .push(Row::Binding(group, binding));
+            y += row_height;
+            tallest = tallest.max(y);
+        }
+    }
+    (columns, tallest)
+}
+
+// Flow groups into at most the number of columns, with the tallest as short as can be
+fn balance(groups: &[(u32, usize)], row_height: u32, count: usize) -> (Vec<Vec<Row>>, u32) {
+    let total: u32 = groups
+        .iter()
+        .map(|&(header_height, count)| header_height + count as u32 * row_height)
+        .sum();
+    let (mut low, mut high) = (total / count.max(1) as u32, total);
+    while low < high {
+        let middle = (low + high) / 2;
+        if flow(groups, row_height, middle).0.len() <= count {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    flow(groups, row_height, high)
+}
+
+// A key sequence from left to right, separated by commas, drawn from the current
+// point if asked. Returns its width.
+pub fn process_keys_help(
+    keys: &[KeySpec],
+    cairo_context: &cairo::Context,
+    text_font: &pango::FontDescription,
+    key_font: &pango::FontDescription,
+    symbol_font: &pango::FontDescription,
+    draw: bool,
+) -> u32 {
+    let layout = match pangocairo::functions::create_layout(cairo_context) {
+        Some(layout) => layout,
+        None => return 0,
+    };
+    layout.set_font_description(text_font);
+    layout.set_text(", ");
+    let separator_width = layout.get_pixel_size().0 as u32;
+
+    let (x, y) = cairo_context.get_current_point();
+    let mut width = 0;
+    for (index, key) in keys.iter().enumerate() {
+        if index > 0 {
+            if draw {
+                cairo_context.move_to(x + width as f64, y);
+                pangocairo::functions::show_layout(cairo_context, &layout);
+            }
+            width += separator_width;
+        }
+        // Modifiers are drawn to the left of the key
+        let (w1, w2) = key.process_help(cairo_context, key_font, symbol_font, false);
+        if draw {
+            cairo_context.move_to(x + (width + w1) as f64, y);
+            key.process_help(cairo_context, key_font, symbol_font, true);
+        }
+        width += w1 + w2;
+    }
+    width
+}
+
+// The height of the tallest description wrapped to the width, with padding above
+// and below, or nothing if there are no descriptions
+fn footer_height<'a>(
+    layout: &pango::Layout,
+    descriptions: impl Iterator<Item = &'a str>,
+    width: u32,
+    padding: u32,
+) -> u32 {
+    layout.set_width(width as i32 * pango::SCALE);
+    layout.set_wrap(pango::WrapMode::WordChar);
+    let tallest = descriptions
+        .map(|description| {
+            layout.set_text(description);
+            layout.get_pixel_size().1 as u32
+        })
+        .max();
+    layout.set_width(-1);
+    tallest.map_or(0, |height| padding + height + padding)
+}
+
+// The height of a row of text, which is that of the tallest font
+pub fn row_height(layout: &pango::Layout, fonts: &[&pango::FontDescription]) -> u32 {
+    fonts
+        .iter()
+        .map(|font| {
+            layout.set_font_description(*font);
+            layout.set_text("Ag");
+            layout.get_pixel_size().1 as u32
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+// Pango markup with the characters at the given positions emphasised
+fn highlight_markup(text: &str, positions: &[usize], color: &str) -> String {
+    let mut markup = String::new();
+    for (index, c) in text.chars().enumerate() {
+        let is_highlighted = positions.contains(&index);
+        if is_highlighted {
+            markup.push_str(&format!("<span foreground=\"{}\" weight=\"bold\">", color));
+        }
+        match c {
+            '&' => markup.push_str("&amp;"),
+            '<' => markup.push_str("&lt;"),
+            '>' => markup.push_str("&gt;"),
+            _ => markup.push(c),
+        }
+        if is_highlighted {
+            markup.push_str("</span>");
+        }
+    }
+    markup
+}
+
 impl Drop for HelpWindow {
     fn drop(&mut self) {
-        xcb::destroy_window(&connection::connection(), self.window);
+        self.destroy_window();
     }
 }
 
-impl Keystroke {
+// An override redirect window, with a 32 bit visual and a colormap of its own if
+// it's to be translucent
+fn create_window(argb_visual: Option<xcb::Visualid>) -> (xcb::Window, Option<xcb::Colormap>) {
+    let connection = connection::connection();
+    let screen = connection.get_setup().roots().nth(0).unwrap();
+
+    let mut values = vec![
+        (
+            xcb::CW_EVENT_MASK,
+            xcb::EVENT_MASK_EXPOSURE
+                | xcb::EVENT_MASK_BUTTON_PRESS
+                | xcb::EVENT_MASK_POINTER_MOTION
+                | xcb::EVENT_MASK_LEAVE_WINDOW,
+        ),
+        (xcb::CW_OVERRIDE_REDIRECT, 1),
+    ];
+    let (depth, visual, colormap) = match argb_visual {
+        Some(visual) => {
+            // A border pixel is required when the depth differs from the parent's
+            let colormap = connection.generate_id();
+            xcb::create_colormap(
+                connection,
+                xcb::COLORMAP_ALLOC_NONE as u8,
+                colormap,
+                screen.root(),
+                visual,
+            );
+            values.push((xcb::CW_BACK_PIXEL, 0));
+            values.push((xcb::CW_BORDER_PIXEL, 0));
+            values.push((xcb::CW_COLORMAP, colormap));
+            (32, visual, Some(colormap))
+        }
+        None => {
+            values.push((xcb::CW_BACK_PIXEL, Theme::default().background.pixel()));
+            (xcb::COPY_FROM_PARENT as u8, screen.root_visual(), None)
+        }
+    };
+
+    let window = connection.generate_id();
+    xcb::create_window(
+        &connection,
+        depth,
+        window,
+        screen.root(),
+        -100,
+        -100,
+        1,
+        1,
+        0, // the theme's border is drawn along with the rest
+        xcb::WINDOW_CLASS_INPUT_OUTPUT as u16,
+        visual,
+        &values,
+    );
+
+    (window, colormap)
+}
+
+impl KeySpec {
+    // As the help window shows it, e.g. "⌘⇧⇥"
+    pub fn display_form(&self) -> String {
+        let mut text: String = MODIFIER_NAME_DISPLAY_FORM
+            .iter()
+            .filter(|(name, _, _)| self.modifiers().iter().any(|m| m == name))
+            .map(|(_, display_form, _)| *display_form)
+            .collect();
+        text.push_str(
+            KEYSYM_NAME_DISPLAY_FORM
+                .get(self.key())
+                .map_or(self.key(), |(display_form, _)| display_form),
+        );
+        text
+    }
+
     fn process_help(
         &self,
         cairo_context: &cairo::Context,
@@ -478,25 +1447,6 @@ impl Keystroke {
         draw: bool,
     ) -> (u32, u32) {
         if let Some(layout) = pangocairo::functions::create_layout(&cairo_context) {
-            let connection = connection::connection();
-            let key_symbols = xcb_util::keysyms::KeySymbols::new(&connection);
-
-            let (keysym, hide_shift) = match (
-                key_symbols.get_keysym(self.keycode(), 0),
-                key_symbols.get_keysym(self.keycode(), 1),
-            ) {
-                (xcb::base::NO_SYMBOL, xcb::base::NO_SYMBOL) => return (0, 0),
-                (a, xcb::base::NO_SYMBOL) => (a, false),
-                (xcb::base::NO_SYMBOL, b) => (b, true),
-                (a, b) => {
-                    if self.made_with_shift() {
-                        (a, false)
-                    } else {
-                        (b, true)
-                    }
-                }
-            };
-
             layout.set_font_description(text_font);
             let text_baseline = layout.get_baseline();
             layout.set_font_description(symbol_font);
@@ -504,16 +1454,10 @@ impl Keystroke {
             let symbol_baseline_offset =
                 (text_baseline - symbol_baseline) as f64 / pango::SCALE as f64;
 
-            let raw_keysym_name = unsafe {
-                std::ffi::CStr::from_ptr(x11::xlib::XKeysymToString(keysym.into()))
-                    .to_str()
-                    .unwrap()
-            };
-
             let (keysym_name, is_symbol) = KEYSYM_NAME_DISPLAY_FORM
-                .get(raw_keysym_name)
+                .get(self.key())
                 .copied()
-                .unwrap_or_else(|| (raw_keysym_name, false));
+                .unwrap_or_else(|| (self.key(), false));
             layout.set_font_description(if is_symbol { symbol_font } else { text_font });
             layout.set_text(keysym_name);
             let width_2 = layout.get_pixel_size().0;
@@ -534,18 +1478,7 @@ impl Keystroke {
             cairo_context.rel_move_to(-1.0, 0.0);
 
             for (name, display_form, is_symbol) in &*MODIFIER_NAME_DISPLAY_FORM {
-                if match *name {
-                    "shift" => {
-                        !hide_shift && self.modifiers() & xcb::KEY_BUT_MASK_SHIFT as u16 != 0
-                    }
-                    "control" => self.modifiers() & xcb::KEY_BUT_MASK_CONTROL as u16 != 0,
-                    "alt" => self.modifiers() & xcb::KEY_BUT_MASK_MOD_1 as u16 != 0,
-                    "mod2" => self.modifiers() & xcb::KEY_BUT_MASK_MOD_2 as u16 != 0,
-                    "hyper" => self.modifiers() & xcb::KEY_BUT_MASK_MOD_3 as u16 != 0,
-                    "super" => self.modifiers() & xcb::KEY_BUT_MASK_MOD_4 as u16 != 0,
-                    "mod5" => self.modifiers() & xcb::KEY_BUT_MASK_MOD_5 as u16 != 0,
-                    _ => false,
-                } {
+                if self.modifiers().iter().any(|m| m == name) {
                     layout.set_font_description(if *is_symbol { symbol_font } else { text_font });
                     layout.set_text(display_form);
                     let w = layout.get_pixel_size().0;
@@ -572,32 +1505,36 @@ impl Keystroke {
 
 lazy_static! {
     static ref MODIFIER_NAME_DISPLAY_FORM: Vec<(&'static str, &'static str, bool)> = {
+        // Names are the canonical ones of keystroke::KeySpec
         vec![
-            ("super", "\u{2318}", true),
-            ("shift", "\u{21e7}", true),
-            ("alt", "\u{2325}", true),
-            ("control", "\u{2303}", true),
-            ("mod2", "mod2-", false),
-            ("mod5", "mod5-", false),
-            ("hyper", "hyper-", false),
+            ("Super", "\u{2318}", true),
+            ("Shift", "\u{21e7}", true),
+            ("Alt", "\u{2325}", true),
+            ("Control", "\u{2303}", true),
+            ("Meta", "meta-", false),
+            ("NumLock", "num-", false),
+            ("ISO_Level3_Shift", "level3-", false),
+            ("CapsLock", "caps-", false),
+            ("ScrollLock", "scroll-", false),
+            ("Hyper", "hyper-", false),
         ]
     };
     static ref KEYSYM_NAME_DISPLAY_FORM: HashMap<&'static str, (&'static str, bool)> = {
         let mut m = HashMap::new();
         m.insert("Tab", ("\u{21e5}", true));
 
-        m.insert("Return", ("&crarr", true));
+        m.insert("Return", ("\u{21b5}", true));
         m.insert("Escape", ("Esc", false));
-        m.insert("BackSpace", ("&#9003", true));
-        m.insert("Delete", ("&#8998", true));
-        m.insert("Up", ("&uarr", true));
-        m.insert("Down", ("&darr", true));
-        m.insert("Left", ("&larr", true));
-        m.insert("Right", ("&rarr", true));
-        m.insert("PageUp", ("&#8670", true));
-        m.insert("PageDown", ("&#8671", true));
-        m.insert("Home", ("&#8598", true));
-        m.insert("End", ("&#8600", true));
+        m.insert("BackSpace", ("\u{232b}", true));
+        m.insert("Delete", ("\u{2326}", true));
+        m.insert("Up", ("\u{2191}", true));
+        m.insert("Down", ("\u{2193}", true));
+        m.insert("Left", ("\u{2190}", true));
+        m.insert("Right", ("\u{2192}", true));
+        m.insert("PageUp", ("\u{21de}", true));
+        m.insert("PageDown", ("\u{21df}", true));
+        m.insert("Home", ("\u{2196}", true));
+        m.insert("End", ("\u{2198}", true));
         m.insert("space", ("\u{2423}", true));
 
         m.insert("plus", ("+", false));
@@ -690,3 +1627,86 @@ lazy_static! {
         m
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Row::*;
+
+    #[test]
+    fn flow_fills_a_column_before_the_next() {
+        let groups = [(10, 2), (10, 1)];
+        assert_eq!(
+            flow(&groups, 10, 100),
+            (
+                vec![vec![
+                    Group(0),
+                    Binding(0, 0),
+                    Binding(0, 1),
+                    Group(1),
+                    Binding(1, 0)
+                ]],
+                50
+            )
+        );
+        assert_eq!(
+            flow(&groups, 10, 40),
+            (
+                vec![
+                    vec![Group(0), Binding(0, 0), Binding(0, 1)],
+                    vec![Group(1), Binding(1, 0)]
+                ],
+                30
+            )
+        );
+        assert_eq!(flow(&[], 10, 100), (vec![vec![]], 0));
+    }
+
+    #[test]
+    fn flow_splits_groups_too_tall_for_a_column() {
+        assert_eq!(
+            flow(&[(0, 2), (10, 5)], 10, 30),
+            (
+                vec![
+                    vec![Binding(0, 0), Binding(0, 1)],
+                    // The name isn't left at the bottom of a column on its own
+                    vec![Group(1), Binding(1, 0), Binding(1, 1)],
+                    vec![Binding(1, 2), Binding(1, 3), Binding(1, 4)]
+                ],
+                30
+            )
+        );
+    }
+
+    #[test]
+    fn balance_makes_the_tallest_column_as_short_as_can_be() {
+        let groups = [(10, 2), (10, 2), (10, 2)];
+        let (columns, tallest) = balance(&groups, 10, 3);
+        assert_eq!((columns.len(), tallest), (3, 30));
+        let (columns, tallest) = balance(&groups, 10, 2);
+        assert_eq!((columns.len(), tallest), (2, 60));
+        assert_eq!(columns[1], [Group(2), Binding(2, 0), Binding(2, 1)]);
+        let (columns, tallest) = balance(&groups, 10, 1);
+        assert_eq!((columns.len(), tallest), (1, 90));
+    }
+
+    #[test]
+    fn settings_are_validated() {
+        let validate = |string: &str| toml::from_str::<Settings>(string).unwrap().validate();
+        assert_eq!(validate(""), Ok(()));
+        assert_eq!(
+            validate("delay = 0.5\nhide_after = 3.0\n[theme]\npadding = 4"),
+            Ok(())
+        );
+        for &string in &[
+            "delay = -1.0",
+            "delay = nan",
+            "delay = 100000.0",
+            "hide_after = inf",
+            "hide_after = -0.5",
+            "[theme]\npadding = 'wide'",
+        ] {
+            assert!(validate(string).is_err(), "{}", string);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use super::{connection, reload};
+use crossbeam::channel::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+pub const IPC_MESSAGE: &str = "COMMANDO_IPC";
+
+// One request per line on the socket, answered by one response per line e.g.
+//
+//   {"command":"mode","name":"applications"}
+//   {"ok":true}
+//
+// The same requests are the arguments to `commando msg`.
+#[derive(Debug, StructOpt, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Enter a mode, as if a binding for it had been pressed
+    #[structopt(name = "mode")]
+    Mode { name: String },
+
+    /// Leave the current mode
+    #[structopt(name = "cancel")]
+    Cancel,
+
+    /// Show or hide the help window
+    #[structopt(name = "toggle-help")]
+    ToggleHelp,
+
+    /// List the bindings that currently apply
+    #[structopt(name = "bindings")]
+    Bindings,
+
+    /// Run the action of the applicable binding with the given label
+    #[structopt(name = "trigger")]
+    Trigger { label: String },
+
+    /// Reload the configuration file
+    #[structopt(name = "reload")]
+    Reload,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bindings: Option<Vec<BindingDescription>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BindingDescription {
+    pub key: String,
+    pub label: String,
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl Response {
+    pub fn ok() -> Response {
+        Response {
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn error(message: String) -> Response {
+        Response {
+            error: Some(message),
+            ..Default::default()
+        }
+    }
+}
+
+pub fn socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(directory) => PathBuf::from(directory).join("commando.sock"),
+        None => env::temp_dir()
+            .join(format!("commando-{}", unsafe { libc::getuid() }))
+            .join("commando.sock"),
+    }
+}
+
+// The socket is only as private as the directory it's in, which is created if
+// necessary and must belong to us and no one else
+fn private_directory(path: &Path) -> io::Result<()> {
+    let directory = path.parent().unwrap_or_else(|| Path::new("/"));
+    if let Err(e) = fs::DirBuilder::new().mode(0o700).create(directory) {
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(e);
+        }
+    }
+    let metadata = fs::symlink_metadata(directory)?;
+    if !metadata.is_dir()
+        || metadata.uid() != unsafe { libc::getuid() }
+        || metadata.mode() & 0o077 != 0
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} isn't a private directory", directory.display()),
+        ));
+    }
+    Ok(())
+}
+
+// The socket file is removed when this is dropped, or when commando is terminated
+// by a signal, which doesn't unwind
+pub struct Socket {
+    path: PathBuf,
+}
+
+impl Socket {
+    fn new(path: PathBuf) -> Socket {
+        match signal_hook::iterator::Signals::new(&[signal_hook::SIGINT, signal_hook::SIGTERM]) {
+            Ok(signals) => {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    if let Some(signal) = signals.forever().next() {
+                        let _ = fs::remove_file(&path);
+                        std::process::exit(128 + signal);
+                    }
+                });
+            }
+            Err(e) => log::error!("Failed to install SIGTERM handler: {}", e),
+        }
+        Socket { path }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Serve the control socket. Requests other than reloads are passed to the key
+// dispatcher, which is woken with an IPC_MESSAGE, along with a channel for the
+// response.
+pub fn spawn(
+    reload_tx: Sender<Option<reload::Reply>>,
+) -> (Receiver<(Request, Sender<Response>)>, Option<Socket>) {
+    let message_type = connection::intern_atom(IPC_MESSAGE);
+    connection::message_window();
+
+    let (request_tx, request_rx) = crossbeam::channel::unbounded();
+
+    let path = socket_path();
+    if let Err(e) = private_directory(&path) {
+        log::error!("Not listening on {}: {}", path.display(), e);
+        return (request_rx, None);
+    }
+    if UnixStream::connect(&path).is_ok() {
+        log::error!(
+            "Not listening on {}, commando is already running",
+            path.display()
+        );
+        return (request_rx, None);
+    }
+    let _ = fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to listen on {}: {}", path.display(), e);
+            return (request_rx, None);
+        }
+    };
+    log::debug!("Listening on {}", path.display());
+    let socket = Socket::new(path);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().filter_map(Result::ok) {
+            let request_tx = request_tx.clone();
+            let reload_tx = reload_tx.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = serve(stream, message_type, &request_tx, &reload_tx) {
+                    log::debug!("IPC connection closed: {}", e);
+                }
+            });
+        }
+    });
+
+    (request_rx, Some(socket))
+}
+
+fn serve(
+    stream: UnixStream,
+    message_type: xcb::Atom,
+    request_tx: &Sender<(Request, Sender<Response>)>,
+    reload_tx: &Sender<Option<reload::Reply>>,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        log::debug!("IPC request {}", line);
+        let response = match serde_json::from_str(&line) {
+            Ok(Request::Reload) => {
+                let (reply_tx, reply_rx) = crossbeam::channel::bounded(1);
+                match reload_tx.send(Some(reply_tx)).map(|()| reply_rx.recv()) {
+                    Ok(Ok(Ok(()))) => Response::ok(),
+                    Ok(Ok(Err(e))) => Response::error(format!("Failed to reload bindings: {}", e)),
+                    _ => Response::error("Reloading is not available".into()),
+                }
+            }
+            Ok(request) => {
+                let (response_tx, response_rx) = crossbeam::channel::bounded(1);
+                if request_tx.send((request, response_tx)).is_err() {
+                    break;
+                }
+                connection::send_message(message_type, 0);
+                match response_rx.recv() {
+                    Ok(response) => response,
+                    Err(_) => break,
+                }
+            }
+            Err(e) => Response::error(format!("Invalid request: {}", e)),
+        };
+
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+    Ok(())
+}
+
+// The client side, for `commando msg`. The response is printed as is, and
+// the result is false if the request failed.
+pub fn send(request: &Request) -> io::Result<bool> {
+    let path = socket_path();
+    private_directory(&path)?;
+    let mut stream = UnixStream::connect(&path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Can't connect to {}: {}", path.display(), e),
+        )
+    })?;
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    print!("{}", line);
+
+    let response: Response = serde_json::from_str(&line)?;
+    Ok(response.ok)
+}
@@ -1,24 +1,65 @@
 use super::{
     connection, help,
+    ipc::{self, BindingDescription, Request, Response},
     keystroke::Keystroke,
-    model::{Action, Context, Model},
+    launcher,
+    model::{Action, Binding, Context, Model},
+    prompt::{Prompt, PromptResult},
+    reload, window_picker,
 };
-use crossbeam::channel::{SendError, Sender};
+use crossbeam::channel::{Receiver, RecvTimeoutError, SendError, Sender};
+use std::{collections::VecDeque, time::Instant};
+
+const TIMEOUT_MESSAGE: &str = "COMMANDO_TIMEOUT";
+
+// What the event loops act on: a key, or the action of a request from another
+// process, which behaves as though a key bound to it had been pressed
+enum Input {
+    Keystroke(Keystroke),
+    Request(Action),
+}
 
 pub struct KeyDispatcher {
     model: Model,
+    reloads: Receiver<Model>,
+    reload_message: xcb::Atom,
+    xkb_event: Option<u8>,
+    screen_change_event: Option<u8>,
+    requests: Receiver<(Request, Sender<Response>)>,
+    ipc_message: xcb::Atom,
+    timeout_message: xcb::Atom,
+    timeout_generation: u32,
+    timer_tx: Sender<Option<(Instant, u32)>>, // when the current timeout runs out
     help_tx: Sender<help::HelpMessage>,
-    keyboard_is_grabbed: bool,
+    is_prompting: bool,
+    pending_keystrokes: VecDeque<Keystroke>, // from the palette, handled before real ones
+    pending_requests: VecDeque<Action>,      // run by whichever event loop is waiting
 }
 
 impl KeyDispatcher {
-    pub fn run(model: Model) {
+    pub fn run(
+        model: Model,
+        reloads: Receiver<Model>,
+        requests: Receiver<(Request, Sender<Response>)>,
+    ) {
         let (sender, receiver) = crossbeam::channel::bounded(0);
         std::thread::spawn(move || help::HelpWindow::new().run(receiver));
+        let timeout_message = connection::intern_atom(TIMEOUT_MESSAGE);
         KeyDispatcher {
             model,
+            reloads,
+            reload_message: connection::intern_atom(reload::RELOAD_MESSAGE),
+            xkb_event: connection::select_keyboard_events(),
+            screen_change_event: connection::select_screen_change_events(),
+            requests,
+            ipc_message: connection::intern_atom(ipc::IPC_MESSAGE),
+            timeout_message,
+            timeout_generation: 0,
+            timer_tx: spawn_timer(timeout_message),
             help_tx: sender,
-            keyboard_is_grabbed: false,
+            is_prompting: false,
+            pending_keystrokes: VecDeque::new(),
+            pending_requests: VecDeque::new(),
         }
         .run_top_level_event_loop()
         .unwrap();
@@ -27,20 +68,34 @@ impl KeyDispatcher {
     fn run_top_level_event_loop(&mut self) -> Result<(), SendError<help::HelpMessage>> {
         log::debug!("Enter top level runloop");
 
-        let context = Context {};
-        let bindings = self.model.get_applicable_bindings("@root", &context);
-        self.help_tx.send(help::HelpMessage::Update(bindings))?;
+        self.help_tx
+            .send(help::HelpMessage::Configure(self.model.get_help_settings()))?;
+        let bindings = self
+            .model
+            .get_applicable_bindings("@root", &Context::current());
+        self.help_tx
+            .send(help::HelpMessage::Update(None, bindings))?;
         connection::grab_keys(&self.model.get_root_grab_keys());
+        window_picker::configure(self.model.get_cancel_keys(), self.model.get_help_settings());
 
-        while let Some(keystroke) = self.wait_for_keystroke() {
+        while let Some((input, context)) = self.wait_for_input("@root") {
             connection::ungrab_keyboard();
             self.help_tx.send(help::HelpMessage::Disarm)?;
-            if let Some(binding) = self.model.get_binding("@root", &context, keystroke) {
-                self.handle_action(&context, &binding.action())?;
-                match binding.action() {
+            let action = match input {
+                Input::Keystroke(keystroke) => self
+                    .model
+                    .get_binding("@root", &context, keystroke)
+                    .map(|binding| binding.action().clone()),
+                Input::Request(action) => Some(action),
+            };
+            if let Some(action) = action {
+                match self.handle_action("@root", &context, &action)? {
                     Action::Mode(_) => {
-                        let bindings = self.model.get_applicable_bindings("@root", &context);
-                        self.help_tx.send(help::HelpMessage::Update(bindings))?;
+                        let bindings = self
+                            .model
+                            .get_applicable_bindings("@root", &Context::current());
+                        self.help_tx
+                            .send(help::HelpMessage::Update(None, bindings))?;
                     }
                     _ => {}
                 }
@@ -55,32 +110,106 @@ impl KeyDispatcher {
     fn run_modal_event_loop(&mut self, mode: &str) -> Result<(), SendError<help::HelpMessage>> {
         log::debug!("Enter runloop for mode {}", mode);
 
-        let context = Context {};
-        let bindings = self.model.get_applicable_bindings(mode, &context);
-        self.help_tx.send(help::HelpMessage::Update(bindings))?;
-        self.help_tx.send(help::HelpMessage::Arm)?;
+        let bindings = self
+            .model
+            .get_applicable_bindings(mode, &Context::current());
+        self.help_tx.send(help::HelpMessage::Update(
+            self.model.get_title(mode),
+            bindings,
+        ))?;
+        if let Some(delay) = self.model.get_help_delay(mode) {
+            self.help_tx.send(help::HelpMessage::Arm(delay))?;
+        }
 
-        while let Some(keystroke) = self.wait_for_keystroke() {
+        self.start_timeout(mode);
+        while let Some((input, context)) = self.wait_for_input(mode) {
+            self.stop_timeout();
             self.help_tx.send(help::HelpMessage::Disarm)?;
-            if let Some(binding) = self.model.get_binding(mode, &context, keystroke) {
-                self.handle_action(&context, &binding.action())?;
-                match binding.action() {
-                    Action::Cancel | Action::Mode(_) | Action::Exec(_) => break,
+            let action = match input {
+                Input::Keystroke(keystroke) => {
+                    match self.model.get_binding(mode, &context, keystroke) {
+                        Some(binding) => Some(binding.action().clone()),
+                        // Like Emacs, a key sequence is abandoned at the first wrong key
+                        None if self.model.is_prefix_mode(mode) && !keystroke.is_modifier() => {
+                            self.help_tx.send(help::HelpMessage::Cancel)?;
+                            break;
+                        }
+                        None => None,
+                    }
+                }
+                Input::Request(action) => Some(action),
+            };
+            if let Some(action) = action {
+                // Only hydra heads, which are Calls, and cancelled searches keep the
+                // mode open
+                match self.handle_action(mode, &context, &action)? {
+                    Action::Cancel | Action::Mode(_) | Action::Exec(_) | Action::Launch => break,
+                    Action::Palette if !self.pending_keystrokes.is_empty() => break,
                     _ => {}
                 }
             }
+            self.start_timeout(mode);
         }
+        self.stop_timeout();
 
         log::debug!("Exit runloop for mode {}", mode);
 
         Ok(())
     }
 
+    // Returns the index of the chosen item
+    fn run_prompt_event_loop(
+        &mut self,
+        mut prompt: Prompt,
+    ) -> Result<Option<usize>, SendError<help::HelpMessage>> {
+        log::debug!("Enter runloop for prompt {}", prompt.title());
+        self.is_prompting = true;
+
+        self.help_tx
+            .send(help::HelpMessage::Prompt(prompt.clone()))?;
+
+        let mut result = None;
+        while let Some(keystroke) = self.wait_for_key_press("@global") {
+            let is_cancel = match self
+                .model
+                .get_binding("@global", &Context::default(), keystroke)
+            {
+                Some(binding) => match binding.action() {
+                    Action::Cancel => true,
+                    _ => false,
+                },
+                None => false,
+            };
+            if is_cancel {
+                break;
+            }
+            match prompt.handle_keystroke(keystroke) {
+                PromptResult::Continue => self
+                    .help_tx
+                    .send(help::HelpMessage::Prompt(prompt.clone()))?,
+                PromptResult::Accept(index) => {
+                    result = Some(index);
+                    break;
+                }
+                PromptResult::Cancel => break,
+            }
+        }
+
+        self.help_tx.send(help::HelpMessage::Cancel)?;
+
+        self.is_prompting = false;
+        log::debug!("Exit runloop for prompt {}", prompt.title());
+
+        Ok(result)
+    }
+
+    // Returns the action that was run, which for a search is the chosen binding's
     fn handle_action(
         &mut self,
+        mode: &str,
         context: &Context,
         action: &Action,
-    ) -> Result<(), SendError<help::HelpMessage>> {
+    ) -> Result<Action, SendError<help::HelpMessage>> {
         match action {
             Action::Cancel => {
                 self.help_tx.send(help::HelpMessage::Cancel)?;
@@ -98,28 +227,309 @@ impl KeyDispatcher {
                 action(context);
             }
 
+            Action::Launch => {
+                self.set_keyboard_is_grabbed(true);
+                let entries = launcher::entries();
+                let names = entries.iter().map(|e| e.name().to_owned()).collect();
+                let choice = self.run_prompt_event_loop(Prompt::new("Launch", names))?;
+                self.set_keyboard_is_grabbed(false);
+                if let Some(index) = choice {
+                    entries[index].launch();
+                }
+            }
+
             Action::Call(action) => action(context),
 
             Action::ToggleHelp => self.help_tx.send(help::HelpMessage::Toggle)?,
+
+            Action::SearchHelp => {
+                if let Some(binding) = self.search_bindings(mode, context)? {
+                    return self.handle_action(mode, context, &binding.action());
+                }
+            }
+
+            // The chosen binding's keys are typed once the mode has been left, since
+            // they start from the root
+            Action::Palette => {
+                if let Some(keystrokes) = self.choose_from_palette(context)? {
+                    self.pending_keystrokes.extend(keystrokes);
+                }
+            }
+        }
+
+        Ok(action.clone())
+    }
+
+    // Let the user pick one of the mode's bindings by typing part of its label or
+    // group, to run it as though its key had been pressed
+    fn search_bindings(
+        &mut self,
+        mode: &str,
+        context: &Context,
+    ) -> Result<Option<Binding>, SendError<help::HelpMessage>> {
+        let bindings: Vec<Binding> = self
+            .model
+            .get_applicable_bindings(mode, context)
+            .into_iter()
+            .filter(|b| is_command(b.action()))
+            .collect();
+        let choices = bindings
+            .iter()
+            .map(|b| match b.group() {
+                Some(group) => format!("{}: {}", group, b.label()),
+                None => b.label().to_owned(),
+            })
+            .collect();
+        let keys = bindings.iter().map(|b| vec![b.key().clone()]).collect();
+        let descriptions = bindings.iter().map(Binding::description).collect();
+
+        let was_grabbed = connection::keyboard_is_grabbed();
+        self.set_keyboard_is_grabbed(true);
+        let prompt = Prompt::new("Search", choices)
+            .with_keys(keys)
+            .with_descriptions(descriptions);
+        let choice = self.run_prompt_event_loop(prompt)?;
+        self.set_keyboard_is_grabbed(was_grabbed);
+
+        Ok(choice.map(|index| bindings[index].clone()))
+    }
+
+    // Let the user pick any binding that can be reached from the root, and return
+    // the keystrokes that lead to it
+    fn choose_from_palette(
+        &mut self,
+        context: &Context,
+    ) -> Result<Option<Vec<Keystroke>>, SendError<help::HelpMessage>> {
+        let reachable: Vec<(Vec<Binding>, Binding)> = self
+            .model
+            .get_reachable_bindings(context)
+            .into_iter()
+            .filter(|(_, b)| is_command(b.action()))
+            .collect();
+        // The modes on the way, then the group, e.g. "Adjust Layout › Ratio: Increase"
+        let choices = reachable
+            .iter()
+            .map(|(path, b)| {
+                let mut names: Vec<String> = path
+                    .iter()
+                    .filter(|p| match p.action() {
+                        Action::Mode(mode) => !self.model.is_prefix_mode(mode),
+                        _ => true,
+                    })
+                    .map(|p| p.label().trim_end_matches('…').trim_end().to_owned())
+                    .collect();
+                names.push(match b.group() {
+                    Some(group) => format!("{}: {}", group, b.label()),
+                    None => b.label().to_owned(),
+                });
+                names.join(" \u{203a} ")
+            })
+            .collect();
+        let keys = reachable
+            .iter()
+            .map(|(path, b)| {
+                path.iter()
+                    .chain(Some(b))
+                    .map(|b| b.key().clone())
+                    .collect()
+            })
+            .collect();
+        let descriptions = reachable.iter().map(|(_, b)| b.description()).collect();
+
+        let was_grabbed = connection::keyboard_is_grabbed();
+        self.set_keyboard_is_grabbed(true);
+        let prompt = Prompt::new("Run", choices)
+            .with_keys(keys)
+            .with_descriptions(descriptions);
+        let choice = self.run_prompt_event_loop(prompt)?;
+        self.set_keyboard_is_grabbed(was_grabbed);
+
+        // Nothing is typed if a key on the way can't be produced by the keyboard
+        Ok(choice.and_then(|index| {
+            let (path, binding) = &reachable[index];
+            path.iter()
+                .chain(Some(binding))
+                .map(|b| b.keystrokes().first().copied())
+                .collect()
+        }))
+    }
+
+    // Swap in the most recently reloaded model, if any. Modal state is kept, so the
+    // current mode continues with its new bindings.
+    fn reload(&mut self, mode: &str) -> Result<(), SendError<help::HelpMessage>> {
+        if let Some(model) = self.reloads.try_iter().last() {
+            log::info!("Reloaded bindings");
+            self.model = model;
+            connection::regrab_keys(&self.model.get_root_grab_keys());
+            window_picker::configure(self.model.get_cancel_keys(), self.model.get_help_settings());
+            self.help_tx
+                .send(help::HelpMessage::Configure(self.model.get_help_settings()))?;
+            let bindings = self
+                .model
+                .get_applicable_bindings(mode, &Context::current());
+            self.help_tx.send(help::HelpMessage::Update(
+                self.model.get_title(mode),
+                bindings,
+            ))?;
         }
 
         Ok(())
     }
 
-    fn wait_for_keystroke(&mut self) -> Option<Keystroke> {
+    // Bindings keep the keys they were written with, so they can be resolved again
+    // with the new keyboard mapping and the root keys regrabbed
+    fn handle_mapping_change(&mut self, mode: &str) -> Result<(), SendError<help::HelpMessage>> {
+        log::info!("Keyboard mapping changed, resolving keys again");
+        connection::forget_keyboard_mapping();
+        self.model.resolve_keys();
+        connection::regrab_keys(&self.model.get_root_grab_keys());
+        window_picker::configure(self.model.get_cancel_keys(), self.model.get_help_settings());
+        let bindings = self
+            .model
+            .get_applicable_bindings(mode, &Context::current());
+        self.help_tx.send(help::HelpMessage::Update(
+            self.model.get_title(mode),
+            bindings,
+        ))
+    }
+
+    // The mode's timeout restarts with every keystroke. A message may already be on
+    // its way when the timer is stopped, so each timeout carries a generation and only
+    // the latest is acted on.
+    fn start_timeout(&mut self, mode: &str) {
+        if let Some(timeout) = self.model.get_timeout(mode) {
+            self.timeout_generation = self.timeout_generation.wrapping_add(1);
+            let _ = self
+                .timer_tx
+                .send(Some((Instant::now() + timeout, self.timeout_generation)));
+        }
+    }
+
+    fn stop_timeout(&mut self) {
+        self.timeout_generation = self.timeout_generation.wrapping_add(1);
+        let _ = self.timer_tx.send(None);
+    }
+
+    // Returns true if an action was queued for the waiting event loop. Actions that
+    // are requested by other processes are responded to straight away since they may
+    // enter a mode, and a mode that times out is cancelled.
+    fn handle_client_message(&mut self, mode: &str, event: &xcb::GenericEvent) -> bool {
+        let message_event: &xcb::ClientMessageEvent = unsafe { xcb::cast_event(&event) };
+        if message_event.type_() == self.reload_message {
+            self.reload(mode).unwrap();
+        } else if message_event.type_() == self.ipc_message {
+            let mut is_queued = false;
+            for (request, response_tx) in self.requests.try_iter().collect::<Vec<_>>() {
+                let (response, action) = self.handle_request(mode, request);
+                let _ = response_tx.send(response);
+                is_queued |= action.is_some();
+                self.pending_requests.extend(action);
+            }
+            return is_queued;
+        } else if message_event.type_() == self.timeout_message
+            && message_event.data().data32()[0] == self.timeout_generation
+        {
+            log::debug!("Mode {} timed out", mode);
+            self.pending_requests.push_back(Action::Cancel);
+            return true;
+        }
+        false
+    }
+
+    fn handle_request(&self, mode: &str, request: Request) -> (Response, Option<Action>) {
+        let context = Context::current();
+        let action = match request {
+            Request::Bindings => {
+                let bindings = self
+                    .model
+                    .get_applicable_bindings(mode, &context)
+                    .iter()
+                    .map(|b| BindingDescription {
+                        key: b.key().to_string(),
+                        label: b.label().to_owned(),
+                        group: b.group().map(str::to_owned),
+                        description: b.description().map(str::to_owned),
+                    })
+                    .collect();
+                return (
+                    Response {
+                        mode: Some(mode.to_owned()),
+                        bindings: Some(bindings),
+                        ..Response::ok()
+                    },
+                    None,
+                );
+            }
+            Request::Mode { name } => match self.model.get_mode(&name) {
+                Some(mode) => Action::Mode(mode),
+                None => return (Response::error(format!("No mode named '{}'", name)), None),
+            },
+            Request::Trigger { label } => match self
+                .model
+                .get_applicable_bindings(mode, &context)
+                .into_iter()
+                .find(|b| b.label() == label)
+            {
+                Some(binding) => binding.action().clone(),
+                None => {
+                    return (
+                        Response::error(format!("No binding labelled '{}' applies", label)),
+                        None,
+                    )
+                }
+            },
+            Request::Cancel => Action::Cancel,
+            Request::ToggleHelp => Action::ToggleHelp,
+            Request::Reload => {
+                return (
+                    Response::error("Reloads are handled by the IPC thread".into()),
+                    None,
+                )
+            }
+        };
+
+        if self.is_prompting {
+            (Response::error("Busy with a prompt".into()), None)
+        } else {
+            (Response::ok(), Some(action))
+        }
+    }
+
+    // The context is that of the window focused when the input arrived, which is
+    // only looked up once for each
+    fn wait_for_input(&mut self, mode: &str) -> Option<(Input, Context)> {
+        if let Some(key) = self.pending_keystrokes.pop_front() {
+            log::debug!("Got keystroke {} from the palette", key);
+            return Some((Input::Keystroke(key), Context::current()));
+        }
+        if let Some(action) = self.pending_requests.pop_front() {
+            return Some((Input::Request(action), Context::current()));
+        }
+
         let mut last_modifier = None;
         while let Some(event) = connection::wait_for_event() {
-            match event.response_type() {
+            // The top bit is set on events sent by clients, such as our own messages
+            match event.response_type() & !0x80 {
                 xcb::KEY_PRESS => {
                     last_modifier = None;
                     let press_event: &xcb::KeyPressEvent = unsafe { xcb::cast_event(&event) };
                     let key = Keystroke::from(press_event);
                     if !key.is_modifier() {
-                        if self.wait_for_key_release(press_event.detail())
+                        let context = Context::current();
+                        // Root keys are grabbed regardless of guards, so pass on
+                        // those that don't apply to the focused window
+                        if !connection::keyboard_is_grabbed()
+                            && self.model.get_binding(mode, &context, key).is_none()
+                        {
+                            log::debug!("Replay keystroke {}", key);
+                            connection::replay_keyboard();
+                        } else if self.wait_for_key_release(mode, press_event.detail())
                             == Some(press_event.state())
                         {
                             log::debug!("Got keystroke {}", key);
-                            return Some(key);
+                            return Some((Input::Keystroke(key), context));
+                        } else if let Some(action) = self.pending_requests.pop_front() {
+                            return Some((Input::Request(action), context));
                         }
                     } else {
                         last_modifier = Some((key, press_event.detail()));
@@ -131,27 +541,147 @@ impl KeyDispatcher {
                     if let Some((key, detail)) = last_modifier {
                         if detail == release_event.detail() {
                             log::debug!("Got keystroke {}", key);
-                            return Some(key);
+                            return Some((Input::Keystroke(key), Context::current()));
                         }
                     }
                     last_modifier = None;
                 }
 
-                xcb::EXPOSE => {
-                    self.help_tx.send(help::HelpMessage::Draw).unwrap();
+                xcb::BUTTON_PRESS | xcb::MOTION_NOTIFY | xcb::LEAVE_NOTIFY => {
+                    if let Some(key) = self.handle_pointer_event(&event) {
+                        log::debug!("Got keystroke {} from a click", key);
+                        return Some((Input::Keystroke(key), Context::current()));
+                    }
+                }
+
+                _ => {
+                    if let Some(input) = self.handle_common_event(mode, &event) {
+                        return Some((input, Context::current()));
+                    }
+                }
+            }
+        }
+
+        return None;
+    }
+
+    // Unlike wait_for_input this doesn't wait for the key to be released, which
+    // suits typing where keys overlap
+    fn wait_for_key_press(&mut self, mode: &str) -> Option<Keystroke> {
+        while let Some(event) = connection::wait_for_event() {
+            // The top bit is set on events sent by clients, such as our own messages
+            match event.response_type() & !0x80 {
+                xcb::KEY_PRESS => {
+                    let press_event: &xcb::KeyPressEvent = unsafe { xcb::cast_event(&event) };
+                    let key = Keystroke::from(press_event);
+                    if !key.is_modifier() {
+                        log::debug!("Got keystroke {}", key);
+                        return Some(key);
+                    }
+                }
+
+                // The wheel pages through the choices
+                xcb::BUTTON_PRESS => {
+                    let press_event: &xcb::ButtonPressEvent = unsafe { xcb::cast_event(&event) };
+                    let name = match press_event.detail() {
+                        4 => "Prior",
+                        5 => "Next",
+                        _ => continue,
+                    };
+                    if let Some(&key) = Keystroke::make(&[], name).first() {
+                        return Some(key);
+                    }
                 }
 
-                _ => {}
+                // Queued actions are left for wait_for_input
+                _ => {
+                    if let Some(Input::Request(action)) = self.handle_common_event(mode, &event) {
+                        self.pending_requests.push_front(action);
+                        return None;
+                    }
+                }
             }
         }
 
         return None;
     }
 
-    fn wait_for_key_release(&mut self, keycode: xcb::Keycode) -> Option<u16> {
+    // Events that every event loop handles the same way. An action that a client
+    // message queues is returned for the waiting event loop.
+    fn handle_common_event(&mut self, mode: &str, event: &xcb::GenericEvent) -> Option<Input> {
+        // The top bit is set on events sent by clients, such as our own messages
+        match event.response_type() & !0x80 {
+            xcb::EXPOSE => {
+                self.help_tx.send(help::HelpMessage::Draw).unwrap();
+            }
+
+            xcb::MAPPING_NOTIFY => {
+                let mapping_event: &xcb::MappingNotifyEvent = unsafe { xcb::cast_event(event) };
+                if mapping_event.request() != xcb::MAPPING_POINTER as u8 {
+                    self.handle_mapping_change(mode).unwrap();
+                }
+            }
+
+            event_type if Some(event_type) == self.xkb_event => {
+                self.handle_mapping_change(mode).unwrap()
+            }
+
+            event_type if Some(event_type) == self.screen_change_event => {
+                self.help_tx.send(help::HelpMessage::Reposition).unwrap();
+            }
+
+            xcb::CLIENT_MESSAGE => {
+                if self.handle_client_message(mode, event) {
+                    return self.pending_requests.pop_front().map(Input::Request);
+                }
+            }
+
+            _ => {}
+        }
+        None
+    }
+
+    // The help window is told where the pointer is, and a click on one of its
+    // bindings is taken as that binding's keystroke
+    fn handle_pointer_event(&mut self, event: &xcb::GenericEvent) -> Option<Keystroke> {
+        match event.response_type() & !0x80 {
+            xcb::BUTTON_PRESS => {
+                let press_event: &xcb::ButtonPressEvent = unsafe { xcb::cast_event(event) };
+                let (x, y) = (press_event.event_x(), press_event.event_y());
+                match press_event.detail() {
+                    1 => {
+                        let (reply_tx, reply) = crossbeam::channel::bounded(1);
+                        self.help_tx
+                            .send(help::HelpMessage::Click(x as i32, y as i32, reply_tx))
+                            .unwrap();
+                        return reply.recv().ok().and_then(|key| key);
+                    }
+                    4 => self.help_tx.send(help::HelpMessage::Scroll(-1)).unwrap(),
+                    5 => self.help_tx.send(help::HelpMessage::Scroll(1)).unwrap(),
+                    _ => {}
+                }
+            }
+
+            xcb::MOTION_NOTIFY => {
+                let motion_event: &xcb::MotionNotifyEvent = unsafe { xcb::cast_event(event) };
+                let position = (motion_event.event_x() as i32, motion_event.event_y() as i32);
+                self.help_tx
+                    .send(help::HelpMessage::Hover(Some(position)))
+                    .unwrap();
+            }
+
+            xcb::LEAVE_NOTIFY => self.help_tx.send(help::HelpMessage::Hover(None)).unwrap(),
+
+            _ => {}
+        }
+        None
+    }
+
+    fn wait_for_key_release(&mut self, mode: &str, keycode: xcb::Keycode) -> Option<u16> {
         let mut is_cancelled = false;
         while let Some(event) = connection::wait_for_event() {
-            match event.response_type() {
+            // The top bit is set on events sent by clients, such as our own messages
+            match event.response_type() & !0x80 {
                 xcb::KEY_RELEASE => {
                     let release_event: &xcb::KeyReleaseEvent = unsafe { xcb::cast_event(&event) };
                     if release_event.detail() == keycode {
@@ -167,11 +697,13 @@ impl KeyDispatcher {
                     is_cancelled = true;
                 }
 
-                xcb::EXPOSE => {
-                    self.help_tx.send(help::HelpMessage::Draw).unwrap();
+                // Queued actions are left for wait_for_input
+                _ => {
+                    if let Some(Input::Request(action)) = self.handle_common_event(mode, &event) {
+                        self.pending_requests.push_front(action);
+                        return None;
+                    }
                 }
-
-                _ => {}
             }
         }
 
@@ -179,13 +711,49 @@ impl KeyDispatcher {
     }
 
     fn set_keyboard_is_grabbed(&mut self, keyboard_is_grabbed: bool) {
-        if keyboard_is_grabbed != self.keyboard_is_grabbed {
+        if keyboard_is_grabbed != connection::keyboard_is_grabbed() {
             if keyboard_is_grabbed {
                 connection::grab_keyboard();
             } else {
                 connection::ungrab_keyboard();
             }
-            self.keyboard_is_grabbed = keyboard_is_grabbed;
         }
     }
 }
+
+// Mode timeouts are kept by one thread, which is told when the timeout restarts or
+// stops, and sends a TIMEOUT_MESSAGE with the timeout's generation when it runs out
+fn spawn_timer(message_type: xcb::Atom) -> Sender<Option<(Instant, u32)>> {
+    let (timer_tx, timer_rx) = crossbeam::channel::unbounded();
+    std::thread::spawn(move || {
+        let mut timeout: Option<(Instant, u32)> = None;
+        loop {
+            let received = match timeout {
+                Some((deadline, _)) => {
+                    timer_rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => timer_rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            timeout = match received {
+                Ok(timeout) => timeout,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some((_, generation)) = timeout {
+                        connection::send_message(message_type, generation);
+                    }
+                    None
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+        }
+    });
+    timer_tx
+}
+
+// Whether the action is worth choosing from a search or the palette, unlike those
+// that only control commando itself
+fn is_command(action: &Action) -> bool {
+    match action {
+        Action::Cancel | Action::ToggleHelp | Action::SearchHelp | Action::Palette => false,
+        _ => true,
+    }
+}
@@ -1,4 +1,4 @@
-use super::connection::{connection, modifier_keycodes};
+use super::connection::{connection, modifier_keycodes, modifier_map};
 use std::{
     cmp::{Ord, Ordering},
     fmt,
@@ -84,20 +84,14 @@ impl Keystroke {
                 let connection = connection();
                 let key_symbols = xcb_util::keysyms::KeySymbols::new(&connection);
 
-                // TODO: look these up dynamically using the xmodmap code in elucidate.rs
-                let mod_mask = modifiers.iter().fold(0, |accum, &m| {
-                    accum
-                        | match m {
-                            "Hyper" => xcb::KEY_BUT_MASK_MOD_3 as u16,
-                            "Super" | "Windows" | "Win" | "Command" | "Cmd" => {
-                                xcb::KEY_BUT_MASK_MOD_4 as u16
-                            }
-                            "Control" | "Ctrl" => xcb::KEY_BUT_MASK_CONTROL as u16,
-                            "Alt" | "Opt" | "Meta" => xcb::KEY_BUT_MASK_MOD_1 as u16,
-                            "Shift" => xcb::KEY_BUT_MASK_SHIFT as u16,
-                            _ => 0,
-                        }
-                });
+                let mut mod_mask = 0;
+                // A missing modifier is warned about by the model, which knows the binding
+                for &m in modifiers {
+                    match Self::modifier_mask(m) {
+                        Some(mask) => mod_mask |= mask,
+                        None => return Vec::new(),
+                    }
+                }
 
                 let mut result = Vec::new();
                 let keysym = xkbcommon::xkb::keysym_from_name(key, xkbcommon::xkb::KEYSYM_NO_FLAGS);
@@ -131,14 +125,6 @@ impl Keystroke {
         }
     }
 
-    // pub fn parse(string: &str) -> Vec<Self> {
-    //     let tokens: Vec<&str> = string.split('-').collect();
-    //     match tokens.split_last() {
-    //         Some((keysym_name, raw_modifiers)) => Self::make(raw_modifiers, keysym_name),
-    //         None => Default::default(),
-    //     }
-    // }
-
     pub fn modifiers(&self) -> u16 {
         self.modifiers
     }
@@ -147,14 +133,41 @@ impl Keystroke {
         self.keycode
     }
 
-    pub fn made_with_shift(&self) -> bool {
-        self.made_with_shift
+    pub fn with_modifiers(&self, modifiers: u16) -> Self {
+        Self {
+            modifiers: self.modifiers | modifiers,
+            ..*self
+        }
+    }
+
+    pub fn without_modifiers(&self, modifiers: u16) -> Self {
+        Self {
+            modifiers: self.modifiers & !modifiers,
+            ..*self
+        }
+    }
+
+    // The symbol the key produces, taking shift into account
+    pub fn keysym(&self) -> xcb::Keysym {
+        let connection = connection();
+        let key_symbols = xcb_util::keysyms::KeySymbols::new(&connection);
+        let shifted = self.modifiers & xcb::KEY_BUT_MASK_SHIFT as u16 != 0;
+        match key_symbols.get_keysym(self.keycode, if shifted { 1 } else { 0 }) {
+            xcb::base::NO_SYMBOL => key_symbols.get_keysym(self.keycode, 0),
+            keysym => keysym,
+        }
     }
 
     pub fn is_modifier(&self) -> bool {
         modifier_keycodes().contains(&self.keycode)
     }
 
+    // The bits the keyboard layout uses for a modifier, which for Hyper, Super, Meta
+    // and Alt vary
+    pub fn modifier_mask(name: &str) -> Option<u16> {
+        modifier_map().mask(canonical_name(name))
+    }
+
     fn make_left_right(modifiers: &[&str], key: &str) -> Vec<Self> {
         Self::make(modifiers, &format!("{}_L", key))
             .iter()
@@ -187,104 +200,35 @@ impl Display for Keystroke {
         let connection = connection();
         let key_symbols = xcb_util::keysyms::KeySymbols::new(&connection);
 
-        let f1 = {
-            let keysym = key_symbols.get_keysym(self.keycode, 0);
+        let keysym_name = |column| {
+            let keysym = key_symbols.get_keysym(self.keycode, column);
             if keysym == xcb::base::NO_SYMBOL {
                 None
             } else {
-                let keysym_name = unsafe {
+                Some(unsafe {
                     std::ffi::CStr::from_ptr(x11::xlib::XKeysymToString(keysym.into()))
                         .to_str()
                         .unwrap()
-                };
-                Some(format!(
-                    "{}{}{}{}{}{}{}{}",
-                    if self.modifiers & xcb::KEY_BUT_MASK_MOD_3 as u16 != 0 {
-                        "hyper-"
-                    } else {
-                        ""
-                    },
-                    if self.modifiers & xcb::KEY_BUT_MASK_MOD_4 as u16 != 0 {
-                        "super-"
-                    } else {
-                        ""
-                    },
-                    if self.modifiers & xcb::KEY_BUT_MASK_MOD_2 as u16 != 0 {
-                        "mod2-"
-                    } else {
-                        ""
-                    },
-                    if self.modifiers & xcb::KEY_BUT_MASK_MOD_5 as u16 != 0 {
-                        "mod5-"
-                    } else {
-                        ""
-                    },
-                    if self.modifiers & xcb::KEY_BUT_MASK_CONTROL as u16 != 0 {
-                        "control-"
-                    } else {
-                        ""
-                    },
-                    if self.modifiers & xcb::KEY_BUT_MASK_MOD_1 as u16 != 0 {
-                        "alt-"
-                    } else {
-                        ""
-                    },
-                    if self.modifiers & xcb::KEY_BUT_MASK_SHIFT as u16 != 0 {
-                        "shift-"
-                    } else {
-                        ""
-                    },
-                    keysym_name
-                ))
+                })
             }
         };
-        let f2 = {
-            let keysym = key_symbols.get_keysym(self.keycode, 1);
-            if !self.modifiers & xcb::KEY_BUT_MASK_SHIFT as u16 != 0
-                || keysym == xcb::base::NO_SYMBOL
-            {
-                None
-            } else {
-                let keysym_name = unsafe {
-                    std::ffi::CStr::from_ptr(x11::xlib::XKeysymToString(keysym.into()))
-                        .to_str()
-                        .unwrap()
-                };
-                Some(format!(
-                    "{}{}{}{}{}{}{}",
-                    if self.modifiers & xcb::KEY_BUT_MASK_MOD_3 as u16 != 0 {
-                        "hyper-"
-                    } else {
-                        ""
-                    },
-                    if self.modifiers & xcb::KEY_BUT_MASK_MOD_4 as u16 != 0 {
-                        "super-"
-                    } else {
-                        ""
-                    },
-                    if self.modifiers & xcb::KEY_BUT_MASK_MOD_2 as u16 != 0 {
-                        "mod2-"
-                    } else {
-                        ""
-                    },
-                    if self.modifiers & xcb::KEY_BUT_MASK_MOD_5 as u16 != 0 {
-                        "mod5-"
-                    } else {
-                        ""
-                    },
-                    if self.modifiers & xcb::KEY_BUT_MASK_CONTROL as u16 != 0 {
-                        "control-"
-                    } else {
-                        ""
-                    },
-                    if self.modifiers & xcb::KEY_BUT_MASK_MOD_1 as u16 != 0 {
-                        "alt-"
-                    } else {
-                        ""
-                    },
-                    keysym_name
-                ))
-            }
+
+        // e.g. "hyper-control-shift-", named after the keys that the layout uses for
+        // each modifier
+        let prefix = |include_shift| {
+            modifier_map()
+                .names(self.modifiers)
+                .into_iter()
+                .filter(|&name| include_shift || name != "Shift")
+                .map(|name| format!("{}-", name.to_lowercase()))
+                .collect::<String>()
+        };
+
+        let f1 = keysym_name(0).map(|name| format!("{}{}", prefix(true), name));
+        let f2 = if !self.modifiers & xcb::KEY_BUT_MASK_SHIFT as u16 != 0 {
+            None
+        } else {
+            keysym_name(1).map(|name| format!("{}{}", prefix(false), name))
         };
 
         match (f1, f2) {
@@ -296,14 +240,126 @@ impl Display for Keystroke {
     }
 }
 
+// A key as it's written in the bindings e.g. "Cmd + question", with the aliases for
+// modifiers replaced by their canonical names. Keycodes depend on the keyboard
+// layout, so the spec is kept to display the key and to resolve it again.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KeySpec {
+    modifiers: Vec<String>,
+    key: String,
+}
+
+impl KeySpec {
+    pub fn new(modifiers: &[&str], key: &str) -> Self {
+        Self {
+            modifiers: modifiers
+                .iter()
+                .map(|&m| canonical_name(m).to_owned())
+                .collect(),
+            key: match key.rfind('_') {
+                // e.g. "Cmd_L"
+                Some(index) if key[index..] == *"_L" || key[index..] == *"_R" => {
+                    format!("{}{}", canonical_name(&key[..index]), &key[index..])
+                }
+                _ => canonical_name(key).to_owned(),
+            },
+        }
+    }
+
+    /// Parse the same syntax as the `key!` macro e.g. "Cmd + Shift + Tab"
+    pub fn parse(string: &str) -> Option<Self> {
+        let tokens: Vec<&str> = string.split('+').map(|t| t.trim()).collect();
+        match tokens.split_last() {
+            Some((key, modifiers)) if !key.is_empty() && !modifiers.contains(&"") => {
+                Some(Self::new(modifiers, key))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn modifiers(&self) -> &[String] {
+        &self.modifiers
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    // The keystrokes that produce the key with the current keyboard mapping, which
+    // may be none
+    pub fn resolve(&self) -> Vec<Keystroke> {
+        let modifiers: Vec<&str> = self.modifiers.iter().map(String::as_str).collect();
+        Keystroke::make(&modifiers, &self.key)
+    }
+}
+
+impl Display for KeySpec {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        for modifier in &self.modifiers {
+            write!(formatter, "{} + ", modifier)?;
+        }
+        write!(formatter, "{}", self.key)
+    }
+}
+
+fn canonical_name(name: &str) -> &str {
+    match name {
+        "Windows" | "Win" | "Command" | "Cmd" => "Super",
+        "Ctrl" => "Control",
+        "Opt" => "Alt",
+        name => name,
+    }
+}
+
 #[macro_export]
 macro_rules! key {
     // The unfolding of the modifier sequence is required to get around a weakness in Rust's macro pattern matching
-    (@m $($m:ident)* + $key:tt) => { $crate::keystroke::Keystroke::make(&[ $(stringify!($m)),*], stringify!($key)) };
+    (@m $($m:ident)* + $key:tt) => { $crate::keystroke::KeySpec::new(&[ $(stringify!($m)),*], stringify!($key)) };
     ($key:tt) => { key!(@m + $key) };
     ($m1:ident + $key:tt) => { key!(@m $m1 + $key) };
     ($m1:ident + $m2:ident + $key:tt) => { key!(@m $m1 $m2 + $key) };
     ($m1:ident + $m2:ident + $m3:ident + $key:tt) => { key!(@m $m1 $m2 $m3 + $key) };
     ($m1:ident + $m2:ident + $m3:ident + $m4:ident + $key:tt) => { key!(@m $m1 $m2 $m3 $m4 + $key) };
-    ($m1:ident + $m2:ident + $m3:ident + $m4:ident + $m5:ident + $key:tt) => { key!(@m $m1 $m2 $m3 $m3 $m5 + $key) };
+    ($m1:ident + $m2:ident + $m3:ident + $m4:ident + $m5:ident + $key:tt) => { key!(@m $m1 $m2 $m3 $m4 $m5 + $key) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_spec_parses_modifiers_and_key() {
+        let spec = KeySpec::parse("Ctrl + Shift + Tab").unwrap();
+        assert_eq!(spec.modifiers(), ["Control", "Shift"]);
+        assert_eq!(spec.key(), "Tab");
+        assert_eq!(KeySpec::parse(" Cmd+space ").unwrap(), key!(Super + space));
+        assert_eq!(KeySpec::parse("a").unwrap().modifiers(), [] as [String; 0]);
+    }
+
+    #[test]
+    fn key_spec_rejects_missing_parts() {
+        assert_eq!(KeySpec::parse(""), None);
+        assert_eq!(KeySpec::parse("Ctrl +"), None);
+        assert_eq!(KeySpec::parse("Ctrl + + a"), None);
+        assert_eq!(KeySpec::parse("+ a"), None);
+    }
+
+    #[test]
+    fn key_spec_uses_canonical_names() {
+        for alias in &["Windows", "Win", "Command", "Cmd"] {
+            assert_eq!(KeySpec::new(&[alias], "a").modifiers(), ["Super"]);
+        }
+        assert_eq!(KeySpec::new(&["Opt"], "a").modifiers(), ["Alt"]);
+        assert_eq!(KeySpec::new(&[], "Cmd_L").key(), "Super_L");
+        assert_eq!(KeySpec::new(&[], "Ctrl_R").key(), "Control_R");
+        assert_eq!(KeySpec::new(&[], "Cmd").key(), "Super");
+        assert_eq!(KeySpec::new(&[], "KP_Left").key(), "KP_Left");
+    }
+
+    #[test]
+    fn key_spec_displays_as_parsed() {
+        let spec = KeySpec::parse("Cmd + Opt + minus").unwrap();
+        assert_eq!(spec.to_string(), "Super + Alt + minus");
+        assert_eq!(KeySpec::parse(&spec.to_string()), Some(spec));
+    }
 }
@@ -0,0 +1,481 @@
+use super::process;
+use lazy_static::lazy_static;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    ffi::OsStr,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+// Something that can be launched, either from a .desktop file or an executable on $PATH
+pub struct Entry {
+    name: String,
+    command: String,
+}
+
+impl Entry {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn launch(&self) {
+        log::info!("Launch {}", self.name);
+        process::spawn(&self.command);
+    }
+}
+
+lazy_static! {
+    // Scanned on first use, and again whenever the directories change
+    static ref ENTRIES: Mutex<Option<Arc<Vec<Entry>>>> = Mutex::new(None);
+}
+
+pub fn entries() -> Arc<Vec<Entry>> {
+    let mut cache = ENTRIES.lock().unwrap();
+    if let Some(entries) = &*cache {
+        return entries.clone();
+    }
+    let entries = Arc::new(scan());
+    // Without a watcher the entries would go stale, so they're scanned every time
+    if watch_directories() {
+        *cache = Some(entries.clone());
+    }
+    entries
+}
+
+// Desktop applications first, in the order that they override each other,
+// followed by executables on $PATH
+fn scan() -> Vec<Entry> {
+    let mut entries = Vec::new();
+
+    let desktops = current_desktops();
+    let mut seen_ids = HashSet::new();
+    for directory in application_directories() {
+        add_desktop_entries(
+            &directory,
+            &directory,
+            &desktops,
+            &mut seen_ids,
+            &mut entries,
+        );
+    }
+    entries.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+
+    let mut executables = Vec::new();
+    let mut seen_names = HashSet::new();
+    for directory in path_directories() {
+        if let Ok(read_dir) = fs::read_dir(&directory) {
+            for entry in read_dir.filter_map(Result::ok) {
+                let is_executable = is_executable(&entry.path());
+                if let (true, Ok(name)) = (is_executable, entry.file_name().into_string()) {
+                    if seen_names.insert(name.clone()) {
+                        executables.push(Entry {
+                            command: name.clone(),
+                            name,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    executables.sort_by(|a, b| a.name.cmp(&b.name));
+
+    entries.extend(executables);
+    entries
+}
+
+// Rescan in the background when anything is installed or removed, so that the
+// keyboard isn't grabbed while it's done. Application directories that don't exist
+// yet aren't watched, though the scan finds them once they do.
+fn watch_directories() -> bool {
+    let mut inotify = match inotify::Inotify::init() {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            log::error!("Failed to initialise inotify: {}", e);
+            return false;
+        }
+    };
+    for directory in path_directories() {
+        let _ = inotify.add_watch(&directory, watch_mask());
+    }
+    // Desktop files may be in subdirectories, so those are watched as they're created
+    let mut applications = HashMap::new();
+    for directory in application_directories() {
+        watch_applications(&mut inotify, &directory, &mut applications);
+    }
+
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let mut created = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => created_directories(events, &applications),
+                Err(e) => {
+                    log::error!("Failed to read inotify events: {}", e);
+                    *ENTRIES.lock().unwrap() = None;
+                    break;
+                }
+            };
+            // Package managers change many files at once, so wait for them to finish
+            thread::sleep(Duration::from_millis(500));
+            while let Ok(events) = inotify.read_events(&mut buffer) {
+                let mut events = events.peekable();
+                if events.peek().is_none() {
+                    break;
+                }
+                created.extend(created_directories(events, &applications));
+            }
+            for directory in created {
+                watch_applications(&mut inotify, &directory, &mut applications);
+            }
+            let entries = Arc::new(scan());
+            *ENTRIES.lock().unwrap() = Some(entries);
+        }
+    });
+
+    true
+}
+
+fn watch_mask() -> inotify::WatchMask {
+    inotify::WatchMask::CREATE
+        | inotify::WatchMask::CLOSE_WRITE
+        | inotify::WatchMask::MOVED_TO
+        | inotify::WatchMask::MOVED_FROM
+        | inotify::WatchMask::DELETE
+        | inotify::WatchMask::ATTRIB
+}
+
+// Watch an application directory and its subdirectories, remembering where each is
+fn watch_applications(
+    inotify: &mut inotify::Inotify,
+    directory: &Path,
+    applications: &mut HashMap<inotify::WatchDescriptor, PathBuf>,
+) {
+    let mut directories = Vec::new();
+    add_subdirectories(directory, &mut directories);
+    for directory in directories {
+        if let Ok(watch) = inotify.add_watch(&directory, watch_mask()) {
+            applications.insert(watch, directory);
+        }
+    }
+}
+
+// Subdirectories that have appeared in application directories
+fn created_directories<'a>(
+    events: impl Iterator<Item = inotify::Event<&'a OsStr>>,
+    applications: &HashMap<inotify::WatchDescriptor, PathBuf>,
+) -> Vec<PathBuf> {
+    events
+        .filter(|event| {
+            event.mask.contains(inotify::EventMask::ISDIR)
+                && event
+                    .mask
+                    .intersects(inotify::EventMask::CREATE | inotify::EventMask::MOVED_TO)
+        })
+        .filter_map(|event| Some(applications.get(&event.wd)?.join(event.name?)))
+        .collect()
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+// A program named by TryExec, which is looked for on $PATH unless it's a path
+fn is_installed(program: &str) -> bool {
+    if program.contains('/') {
+        is_executable(Path::new(program))
+    } else {
+        path_directories()
+            .iter()
+            .any(|directory| is_executable(&directory.join(program)))
+    }
+}
+
+// The names in $XDG_CURRENT_DESKTOP, which OnlyShowIn and NotShowIn are matched against
+fn current_desktops() -> Vec<String> {
+    env::var("XDG_CURRENT_DESKTOP")
+        .unwrap_or_default()
+        .split(':')
+        .filter(|desktop| !desktop.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn path_directories() -> Vec<PathBuf> {
+    env::var_os("PATH")
+        .map(|path| env::split_paths(&path).collect())
+        .unwrap_or_else(Vec::new)
+}
+
+fn add_subdirectories(directory: &Path, directories: &mut Vec<PathBuf>) {
+    if let Ok(read_dir) = fs::read_dir(directory) {
+        directories.push(directory.to_owned());
+        for entry in read_dir.filter_map(Result::ok) {
+            if entry.path().is_dir() {
+                add_subdirectories(&entry.path(), directories);
+            }
+        }
+    }
+}
+
+fn application_directories() -> Vec<PathBuf> {
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".local/share")));
+    let data_dirs = env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".into());
+
+    data_home
+        .into_iter()
+        .chain(env::split_paths(&data_dirs))
+        .map(|dir| dir.join("applications"))
+        .collect()
+}
+
+fn add_desktop_entries(
+    base: &Path,
+    directory: &Path,
+    desktops: &[String],
+    seen_ids: &mut HashSet<String>,
+    entries: &mut Vec<Entry>,
+) {
+    let read_dir = match fs::read_dir(directory) {
+        Ok(read_dir) => read_dir,
+        Err(_) => return,
+    };
+    for entry in read_dir.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.is_dir() {
+            add_desktop_entries(base, &path, desktops, seen_ids, entries);
+        } else if path.extension().map_or(false, |e| e == "desktop") {
+            // The desktop file id is the path relative to the applications directory
+            // with '/' replaced by '-', and earlier directories take precedence
+            let id = path
+                .strip_prefix(base)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .replace('/', "-");
+            if seen_ids.insert(id) {
+                if let Some(entry) = parse_desktop_file(&path, desktops) {
+                    entries.push(entry);
+                }
+            }
+        }
+    }
+}
+
+fn parse_desktop_file(path: &Path, desktops: &[String]) -> Option<Entry> {
+    let contents = fs::read_to_string(path).ok()?;
+    parse_desktop_entry(&contents, path, desktops)
+}
+
+// Entries are left out if they're hidden, their TryExec program isn't installed,
+// or they're not meant for the current desktop
+fn parse_desktop_entry(contents: &str, path: &Path, desktops: &[String]) -> Option<Entry> {
+    let mut in_desktop_entry = false;
+    let mut name = None;
+    let mut exec = None;
+    let mut terminal = false;
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_desktop_entry = line == "[Desktop Entry]";
+        } else if in_desktop_entry {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next().map(str::trim), parts.next().map(str::trim)) {
+                (Some("Type"), Some(value)) if value != "Application" => return None,
+                (Some("NoDisplay"), Some("true")) | (Some("Hidden"), Some("true")) => return None,
+                (Some("TryExec"), Some(value)) if !is_installed(value) => return None,
+                (Some("OnlyShowIn"), Some(value)) if !is_shown_in(value, desktops) => return None,
+                (Some("NotShowIn"), Some(value)) if is_shown_in(value, desktops) => return None,
+                (Some("Name"), Some(value)) => name = Some(value.to_owned()),
+                (Some("Exec"), Some(value)) => exec = Some(value),
+                (Some("Terminal"), Some(value)) => terminal = value == "true",
+                _ => {}
+            }
+        }
+    }
+
+    let name: String = name?;
+    let command = exec_command(exec?, &name, path)?;
+    Some(Entry {
+        command: if terminal {
+            format!("${{TERMINAL:-xterm}} -e {}", command)
+        } else {
+            command
+        },
+        name,
+    })
+}
+
+// Whether any of the desktops in a list such as "GNOME;KDE;" is current
+fn is_shown_in(list: &str, desktops: &[String]) -> bool {
+    list.split(';')
+        .any(|desktop| desktops.iter().any(|current| current == desktop))
+}
+
+// The Exec key as a shell command. Its arguments are unquoted as the desktop entry
+// specification describes and then quoted for the shell, and the field codes for
+// files and URLs are left out since none are ever supplied. None if the quoting is
+// invalid.
+fn exec_command(exec: &str, name: &str, path: &Path) -> Option<String> {
+    // Values are unescaped before the arguments are split
+    let mut unescaped = String::new();
+    let mut chars = exec.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('s') => unescaped.push(' '),
+                Some('n') => unescaped.push('\n'),
+                Some('t') => unescaped.push('\t'),
+                Some('r') => unescaped.push('\r'),
+                Some('\\') | None => unescaped.push('\\'),
+                Some(c) => {
+                    unescaped.push('\\');
+                    unescaped.push(c);
+                }
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+
+    // Quoted arguments may escape '"', '`', '$' and '\' with a backslash
+    let mut arguments: Vec<(String, bool)> = Vec::new(); // and whether it was quoted
+    let mut argument: Option<(String, bool)> = None;
+    let mut chars = unescaped.chars();
+    while let Some(c) = chars.next() {
+        match (c, &mut argument) {
+            (' ', None) | ('\t', None) | ('\n', None) => {}
+            (' ', Some(_)) | ('\t', Some(_)) | ('\n', Some(_)) => {
+                arguments.extend(argument.take());
+            }
+            ('"', _) => {
+                let mut quoted = argument.take().map(|(a, _)| a).unwrap_or_default();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => quoted.push(chars.next()?),
+                        c => quoted.push(c),
+                    }
+                }
+                argument = Some((quoted, true));
+            }
+            (c, Some((a, _))) => a.push(c),
+            (c, None) => argument = Some((c.to_string(), false)),
+        }
+    }
+    arguments.extend(argument);
+
+    let words: Vec<String> = arguments
+        .into_iter()
+        .filter_map(|(argument, is_quoted)| {
+            if is_quoted {
+                return Some(argument);
+            }
+            // A field code for files or URLs stands for a whole argument
+            let mut expanded = String::new();
+            let mut chars = argument.chars();
+            while let Some(c) = chars.next() {
+                if c != '%' {
+                    expanded.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('%') => expanded.push('%'),
+                    Some('c') => expanded.push_str(name),
+                    Some('k') => expanded.push_str(&path.to_string_lossy()),
+                    Some('f') | Some('F') | Some('u') | Some('U') if argument.len() == 2 => {
+                        return None
+                    }
+                    _ => {}
+                }
+            }
+            Some(expanded).filter(|expanded| !expanded.is_empty())
+        })
+        .map(|word| shell_quote(&word))
+        .collect();
+
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+fn shell_quote(word: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-+=/.,:@%".contains(c);
+    if !word.is_empty() && word.chars().all(is_safe) {
+        word.to_owned()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(exec: &str) -> Option<String> {
+        exec_command(
+            exec,
+            "Editor",
+            Path::new("/usr/share/applications/editor.desktop"),
+        )
+    }
+
+    #[test]
+    fn exec_is_unquoted_and_quoted_again_for_the_shell() {
+        assert_eq!(
+            command("editor --new-window %U"),
+            Some("editor --new-window".into())
+        );
+        assert_eq!(
+            command(r#""/opt/My Editor/editor" "it's \"here\"" "\\$HOME""#),
+            Some(r#"'/opt/My Editor/editor' 'it'\''s "here"' '$HOME'"#.into())
+        );
+        assert_eq!(command(r"editor\sone\ttwo"), Some("editor one two".into()));
+        assert_eq!(
+            command("editor --name=%c 100%%"),
+            Some("editor --name=Editor 100%".into())
+        );
+        assert_eq!(
+            command("editor %k"),
+            Some("editor /usr/share/applications/editor.desktop".into())
+        );
+    }
+
+    #[test]
+    fn invalid_exec_is_left_out() {
+        assert_eq!(command(r#"editor "unterminated"#), None);
+        assert_eq!(command("%F"), None);
+        assert_eq!(command(""), None);
+    }
+
+    #[test]
+    fn entries_are_only_shown_where_they_belong() {
+        let desktops = vec!["XFCE".to_owned()];
+        let entry = |keys: &str| {
+            let contents = format!("[Desktop Entry]\nName=Editor\nExec=editor\n{}", keys);
+            parse_desktop_entry(&contents, Path::new("editor.desktop"), &desktops)
+                .map(|entry| entry.command)
+        };
+        assert_eq!(entry(""), Some("editor".into()));
+        assert_eq!(entry("OnlyShowIn=GNOME;XFCE;"), Some("editor".into()));
+        assert_eq!(entry("OnlyShowIn=GNOME;"), None);
+        assert_eq!(entry("NotShowIn=XFCE;"), None);
+        assert_eq!(entry("NotShowIn=KDE;"), Some("editor".into()));
+        assert_eq!(entry("TryExec=/nonexistent/editor"), None);
+        assert_eq!(entry("TryExec=sh"), Some("editor".into()));
+        assert_eq!(
+            entry("Terminal=true"),
+            Some("${TERMINAL:-xterm} -e editor".into())
+        );
+    }
+}
@@ -9,41 +9,141 @@ mod model;
 
 mod connection;
 mod help;
+mod ipc;
 mod key_dispatcher;
+mod launcher;
+mod process;
+mod prompt;
+mod reload;
+mod theme;
+mod window_picker;
 
 mod ceramic;
+mod config;
+mod ewmh;
+mod export;
 
 use key_dispatcher::KeyDispatcher;
 use model::*;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 struct Args {
     #[structopt(flatten)]
     verbosity: clap_verbosity_flag::Verbosity,
+
+    /// Bindings file to use instead of ~/.config/commando/config.toml,
+    /// reloaded on change or SIGHUP
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Window manager that the built-in bindings control
+    #[structopt(
+        long = "window-manager",
+        default_value = "ceramic",
+        raw(possible_values = r#"&["ceramic", "ewmh"]"#)
+    )]
+    window_manager: String,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Send a request to the running commando
+    #[structopt(name = "msg")]
+    Msg(ipc::Request),
+
+    /// Write a cheat-sheet of all the bindings
+    #[structopt(name = "export")]
+    Export(export::Options),
 }
 
 fn main() {
     let args = Args::from_args();
     args.verbosity.setup_env_logger("commando").unwrap();
-    KeyDispatcher::run(create_model());
+
+    if let Some(Command::Msg(request)) = &args.command {
+        match ipc::send(request) {
+            Ok(true) => std::process::exit(0),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("commando: {}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let path = args.config.clone().or_else(config::default_path);
+    let is_required = args.config.is_some();
+    let window_manager = args.window_manager.clone();
+
+    if let Some(Command::Export(options)) = &args.command {
+        // Exporting doesn't look keys up, so it works without a display
+        let result = create_model(Model::unresolved(), &path, is_required, &window_manager)
+            .map_err(|e| e.to_string())
+            .and_then(|model| export::export(&model, options).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("commando: {}", e);
+            std::process::exit(2);
+        }
+        std::process::exit(0);
+    }
+
+    let model =
+        create_model(Model::new(), &path, is_required, &window_manager).unwrap_or_else(|e| {
+            log::error!("Failed to load bindings, using built-in bindings: {}", e);
+            create_default_model(Model::new(), &window_manager)
+        });
+    let (reload_tx, reloads) = reload::spawn(path.clone(), move || {
+        create_model(Model::new(), &path, is_required, &window_manager)
+    });
+
+    let (requests, _socket) = ipc::spawn(reload_tx);
+
+    KeyDispatcher::run(model, reloads, requests);
 }
 
-fn create_model() -> Model {
-    let mut model = Model::new();
+// The default file is optional, but a file given with --config is required
+fn create_model(
+    mut model: Model,
+    path: &Option<PathBuf>,
+    is_required: bool,
+    window_manager: &str,
+) -> Result<Model, config::Error> {
+    match path {
+        Some(path) if path.exists() => {
+            config::load(path)?.add_bindings(&mut model)?;
+            Ok(model)
+        }
+        Some(path) if is_required => Err(config::Error::Invalid(format!(
+            "{} doesn't exist",
+            path.display()
+        ))),
+        _ => Ok(create_default_model(model, window_manager)),
+    }
+}
 
+fn create_default_model(mut model: Model, window_manager: &str) -> Model {
     model.extend_with(&bindings!(
         global {
             Escape         => { "Cancel Operation" cancel }
             Ctrl + g       => { "Cancel Operation" cancel }
             Cmd + question => { "Toggle/Move Help" toggle help }
+            Cmd + slash    => { "Search Help" search help }
+            Cmd + p        => { "Command Palette" palette }
         }
         root {
             Command => { "Application" => application }
         }
     ));
 
-    ceramic::extend_model(&mut model);
+    match window_manager {
+        "ewmh" => ewmh::extend_model(&mut model),
+        _ => ceramic::extend_model(&mut model),
+    }
 
     model
 }
@@ -1,30 +1,114 @@
-use super::{connection::connection, keystroke::Keystroke};
-use std::{collections::HashMap, sync::Arc};
-
-pub struct Context {}
+use super::{
+    connection, help,
+    keystroke::{KeySpec, Keystroke},
+};
+use lazy_static::lazy_static;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[derive(Debug, Default)]
+pub struct Context {
+    instance: String,
+    class: String,
+    title: String,
+}
 
 impl Context {
+    // Describe the window that currently has the focus
+    pub fn current() -> Context {
+        match connection::focused_window() {
+            Some(window) => Self::for_window(window),
+            None => Default::default(),
+        }
+    }
+
+    pub fn for_window(window: xcb::Window) -> Context {
+        // WM_CLASS is two consecutive null-terminated strings
+        let wm_class =
+            connection::get_string_property(window, xcb::ATOM_WM_CLASS).unwrap_or_default();
+        let mut wm_class = wm_class.split('\0');
+        let instance = wm_class.next().unwrap_or_default().to_owned();
+        let class = wm_class.next().unwrap_or_default().to_owned();
+
+        // Interned once, since every keystroke's context looks it up
+        lazy_static! {
+            static ref NET_WM_NAME: xcb::Atom = connection::intern_atom("_NET_WM_NAME");
+        }
+        let title = connection::get_string_property(window, *NET_WM_NAME)
+            .or_else(|| connection::get_string_property(window, xcb::ATOM_WM_NAME))
+            .unwrap_or_default();
+
+        let context = Context {
+            instance,
+            class,
+            title,
+        };
+        log::debug!("{:?}", context);
+        context
+    }
+
+    // Tests have no windows to describe
+    #[cfg(test)]
+    pub fn with_names(instance: &str, class: &str, title: &str) -> Context {
+        Context {
+            instance: instance.to_owned(),
+            class: class.to_owned(),
+            title: title.to_owned(),
+        }
+    }
+
     pub fn instance(&self) -> String {
-        "".into()
+        self.instance.clone()
     }
 
     pub fn class(&self) -> String {
-        "".into()
+        self.class.clone()
     }
 
-    pub fn connection(&self) -> &xcb::Connection {
-        connection()
+    pub fn title(&self) -> String {
+        self.title.clone()
     }
 }
 
 pub struct Model {
     bindings: HashMap<&'static str, Vec<Binding>>,
+    prefix_titles: HashMap<&'static str, &'static str>, // synthesized mode, keystrokes so far
+    timeouts: HashMap<&'static str, Duration>,
+    help_delays: HashMap<&'static str, Option<Duration>>, // None to never show help
+    ignored_modifier_names: Vec<String>,
+    ignored_modifiers: u16,
+    help_settings: help::Settings,
+    resolves_keys: bool, // whether keys are looked up on the keyboard
 }
 
+// Lock modifiers are ignored by default, so that bindings work whatever their state
+pub const DEFAULT_IGNORED_MODIFIERS: &[&str] = &["CapsLock", "NumLock", "ScrollLock"];
+
 impl Model {
     pub fn new() -> Model {
+        let mut model = Self::unresolved();
+        model.resolves_keys = true;
+        model.resolve_ignored_modifiers();
+        model
+    }
+
+    // A model that never looks its keys up, for exporting without a display
+    pub fn unresolved() -> Model {
         Self {
             bindings: HashMap::new(),
+            prefix_titles: HashMap::new(),
+            timeouts: HashMap::new(),
+            help_delays: HashMap::new(),
+            ignored_modifier_names: DEFAULT_IGNORED_MODIFIERS
+                .iter()
+                .map(|&name| name.to_owned())
+                .collect(),
+            ignored_modifiers: 0,
+            help_settings: Default::default(),
+            resolves_keys: false,
         }
     }
 
@@ -35,18 +119,157 @@ impl Model {
     pub fn add_binding(
         &mut self,
         set: &'static str,
-        keystrokes: Vec<Keystroke>,
+        key: KeySpec,
         label: &'static str,
+        description: Option<&'static str>,
         group: Option<&'static str>,
         guard: Option<Arc<Box<dyn GuardFn>>>,
         action: Action,
     ) {
-        self.bindings
-            .entry(set)
-            .or_default()
-            .extend(keystrokes.iter().map(|&keystroke| {
-                Binding::new(keystroke, label, group, guard.clone(), action.clone())
-            }));
+        let mut binding = Binding::new(key, label, description, group, guard, action);
+        if self.resolves_keys {
+            binding.keystrokes = binding.key.resolve();
+            if binding.keystrokes.is_empty() {
+                match binding
+                    .key
+                    .modifiers()
+                    .iter()
+                    .find(|m| Keystroke::modifier_mask(m).is_none())
+                {
+                    Some(modifier) => log::warn!(
+                        "Key '{}' is not available on this keyboard, which has no {} modifier",
+                        binding.key,
+                        modifier
+                    ),
+                    None => log::warn!("Key '{}' is not available on this keyboard", binding.key),
+                }
+            }
+        }
+        self.bindings.entry(set).or_default().push(binding);
+    }
+
+    // Bind the steps of a key sequence such as "Ctrl + x, Ctrl + f" before the last
+    // to modes that are synthesized on demand, and return the mode that the last
+    // step should be bound in. Sequences that share a prefix share its mode.
+    pub fn add_prefix_modes(
+        &mut self,
+        set: &'static str,
+        group: Option<&'static str>,
+        prefixes: Vec<KeySpec>,
+    ) -> &'static str {
+        let mut mode = set;
+        let mut path = Vec::new();
+        for key in prefixes {
+            let name = key.to_string();
+            path.push(name.clone());
+            let path = path.join(", ");
+            let prefix_mode = intern(&format!("{}/{}", set, path));
+            if !self.prefix_titles.contains_key(prefix_mode) {
+                let title = match set {
+                    "@global" | "@root" => intern(&path),
+                    _ => intern(&format!("{}: {}", set, path)),
+                };
+                self.prefix_titles.insert(prefix_mode, title);
+                self.add_binding(
+                    mode,
+                    key,
+                    intern(&format!("{} …", name)),
+                    None,
+                    group,
+                    None,
+                    Action::Mode(prefix_mode),
+                );
+            }
+            mode = prefix_mode;
+        }
+
+        mode
+    }
+
+    // The name of a mode that can be entered, as it's stored in the model
+    pub fn get_mode(&self, name: &str) -> Option<&'static str> {
+        match name {
+            "@global" | "@root" => None,
+            _ => self.bindings.keys().find(|&&mode| mode == name).copied(),
+        }
+    }
+
+    // The title for the help window, which is only set for synthesized prefix modes
+    pub fn get_title(&self, mode: &str) -> Option<&'static str> {
+        self.prefix_titles.get(mode).copied()
+    }
+
+    pub fn is_prefix_mode(&self, mode: &str) -> bool {
+        self.prefix_titles.contains_key(mode)
+    }
+
+    // A mode with a timeout is left when no key has been pressed for that long, which
+    // suits hydras
+    pub fn set_timeout(&mut self, mode: &'static str, timeout: Duration) {
+        self.timeouts.insert(mode, timeout);
+    }
+
+    pub fn get_timeout(&self, mode: &str) -> Option<Duration> {
+        self.timeouts.get(mode).copied()
+    }
+
+    // How long the mode waits for a key before showing the help window, if it ever
+    // does. Modes without a delay of their own use the one in the help settings.
+    pub fn set_help_delay(&mut self, mode: &'static str, delay: Option<Duration>) {
+        self.help_delays.insert(mode, delay);
+    }
+
+    pub fn get_help_delay(&self, mode: &str) -> Option<Duration> {
+        match self.help_delays.get(mode) {
+            Some(delay) => *delay,
+            None => Some(self.help_settings.delay()),
+        }
+    }
+
+    // Modifiers that are stripped from keystrokes before they're matched, and that
+    // root keys are grabbed with in every combination
+    pub fn set_ignored_modifiers(&mut self, names: &[String]) {
+        self.ignored_modifier_names = names.to_vec();
+        if self.resolves_keys {
+            for name in names {
+                if Keystroke::modifier_mask(name).is_none() {
+                    log::warn!("Modifier {} is not available on this keyboard", name);
+                }
+            }
+        }
+        self.resolve_ignored_modifiers();
+    }
+
+    fn resolve_ignored_modifiers(&mut self) {
+        self.ignored_modifiers = 0;
+        if !self.resolves_keys {
+            return;
+        }
+        for name in &self.ignored_modifier_names {
+            match Keystroke::modifier_mask(name) {
+                Some(mask) => self.ignored_modifiers |= mask,
+                None => log::debug!("Modifier {} is not available on this keyboard", name),
+            }
+        }
+    }
+
+    pub fn set_help_settings(&mut self, settings: help::Settings) {
+        self.help_settings = settings;
+    }
+
+    pub fn get_help_settings(&self) -> help::Settings {
+        self.help_settings.clone()
+    }
+
+    // Look up the keys again from their specs, after the keyboard mapping changes
+    pub fn resolve_keys(&mut self) {
+        if !self.resolves_keys {
+            return;
+        }
+        self.resolve_ignored_modifiers();
+        for binding in self.bindings.values_mut().flat_map(|bs| bs.iter_mut()) {
+            binding.keystrokes = binding.key.resolve();
+        }
     }
 
     pub fn get_applicable_bindings(&self, name: &str, context: &Context) -> Vec<Binding> {
@@ -55,35 +278,190 @@ impl Model {
             .iter()
             .chain(self.bindings.get(name).iter())
             .flat_map(|&bs| bs)
-            .filter(|b| b.apply_guard(context))
+            .filter(|b| self.is_applicable(b, context))
             .cloned()
             .collect()
     }
 
+    // Every binding that applies and can be reached from the root, with the bindings
+    // that enter the modes on the way to it. Each mode is reached the shortest way,
+    // and bindings that only continue a key sequence aren't listed themselves. Keys
+    // that the keyboard can't produce don't apply, so every step can be typed.
+    pub fn get_reachable_bindings(&self, context: &Context) -> Vec<(Vec<Binding>, Binding)> {
+        let mut reachable = Vec::new();
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        visited.insert("@root");
+        queue.push_back(("@root", vec![]));
+        while let Some((mode, path)) = queue.pop_front() {
+            // Global bindings are listed once, at the root
+            let sets: &[&str] = match mode {
+                "@root" => &["@global", "@root"],
+                _ => &[mode],
+            };
+            for binding in sets
+                .iter()
+                .flat_map(|&set| self.bindings.get(set))
+                .flatten()
+                .filter(|b| self.is_applicable(b, context))
+            {
+                if let Action::Mode(next_mode) = binding.action {
+                    if visited.insert(next_mode) {
+                        let mut next_path: Vec<Binding> = path.clone();
+                        next_path.push(binding.clone());
+                        queue.push_back((next_mode, next_path));
+                    }
+                    if self.is_prefix_mode(next_mode) {
+                        continue;
+                    }
+                }
+                reachable.push((path.clone(), binding.clone()));
+            }
+        }
+        reachable
+    }
+
+    // The modes that a cheat-sheet lists: global, root, then the named modes by name.
+    // Prefix modes are listed with the bindings that lead to them.
+    pub fn get_modes(&self) -> Vec<&'static str> {
+        let mut modes: Vec<&'static str> = self
+            .bindings
+            .keys()
+            .copied()
+            .filter(|mode| !mode.starts_with('@') && !self.is_prefix_mode(mode))
+            .collect();
+        modes.sort();
+        ["@global", "@root"]
+            .iter()
+            .copied()
+            .filter(|mode| self.bindings.contains_key(mode))
+            .chain(modes)
+            .collect()
+    }
+
+    // Every binding of a mode whatever the guards, with the key sequence that leads
+    // to it through prefix modes
+    pub fn get_key_sequences(&self, mode: &str) -> Vec<(Vec<KeySpec>, Binding)> {
+        let mut sequences = Vec::new();
+        for binding in self.bindings.get(mode).into_iter().flatten() {
+            match binding.action {
+                Action::Mode(prefix_mode) if self.is_prefix_mode(prefix_mode) => {
+                    for (mut keys, b) in self.get_key_sequences(prefix_mode) {
+                        keys.insert(0, binding.key.clone());
+                        sequences.push((keys, b));
+                    }
+                }
+                _ => sequences.push((vec![binding.key.clone()], binding.clone())),
+            }
+        }
+        sequences
+    }
+
     pub fn get_binding(
         &self,
         set: &str,
         context: &Context,
         keystroke: Keystroke,
     ) -> Option<Binding> {
+        let keystroke = keystroke.without_modifiers(self.ignored_modifiers);
         self.bindings
             .get("@global")
             .iter()
             .chain(self.bindings.get(set).iter())
             .flat_map(|&bs| bs)
-            .find(|b| b.keystroke() == keystroke && b.apply_guard(context))
+            .find(|b| b.keystrokes.contains(&keystroke) && self.is_applicable(b, context))
             .cloned()
     }
 
+    // Prefix bindings aren't guarded themselves, but only apply when something in
+    // their mode does. Keys that the keyboard can't produce don't apply either.
+    fn is_applicable(&self, binding: &Binding, context: &Context) -> bool {
+        !binding.keystrokes.is_empty()
+            && binding.apply_guard(context)
+            && match binding.action {
+                Action::Mode(mode) if self.is_prefix_mode(mode) => self
+                    .bindings
+                    .get(mode)
+                    .iter()
+                    .flat_map(|&bs| bs)
+                    .any(|b| self.is_applicable(b, context)),
+                _ => true,
+            }
+    }
+
+    // Guards are ignored because they depend on the window that has the focus when
+    // the key is pressed. X matches grabs on the exact modifier state, so each key is
+    // grabbed with every combination of the ignored modifiers.
     pub fn get_root_grab_keys(&self) -> Vec<Keystroke> {
-        self.get_applicable_bindings("@root", &Context {})
+        let ignored_combinations = self.ignored_combinations();
+        self.bindings
+            .get("@global")
             .iter()
-            .filter_map(|b| match b.action {
-                Action::Cancel => None,
-                _ => Some(b.keystroke),
+            .chain(self.bindings.get("@root").iter())
+            .flat_map(|&bs| bs)
+            .filter(|b| match b.action {
+                Action::Cancel => false,
+                _ => true,
+            })
+            .flat_map(|b| b.keystrokes.iter().copied())
+            .flat_map(|keystroke| {
+                ignored_combinations
+                    .iter()
+                    .map(move |&modifiers| keystroke.with_modifiers(modifiers))
             })
             .collect()
     }
+
+    // The keys of the global cancel bindings, for event loops outside the key
+    // dispatcher. Like grabs, they're given with every combination of the ignored
+    // modifiers so that they can be compared with key presses as they are.
+    pub fn get_cancel_keys(&self) -> Vec<Keystroke> {
+        let ignored_combinations = self.ignored_combinations();
+        self.bindings
+            .get("@global")
+            .iter()
+            .flat_map(|&bs| bs)
+            .filter(|b| match b.action {
+                Action::Cancel => b.apply_guard(&Context::default()),
+                _ => false,
+            })
+            .flat_map(|b| b.keystrokes.iter().copied())
+            .flat_map(|keystroke| {
+                ignored_combinations
+                    .iter()
+                    .map(move |&modifiers| keystroke.with_modifiers(modifiers))
+            })
+            .collect()
+    }
+
+    fn ignored_combinations(&self) -> Vec<u16> {
+        let mut combinations = vec![0];
+        for bit in (0..8).map(|index| 1 << index) {
+            if self.ignored_modifiers & bit != 0 {
+                for index in 0..combinations.len() {
+                    combinations.push(combinations[index] | bit);
+                }
+            }
+        }
+        combinations
+    }
+}
+
+// The model holds &'static str, so strings that are built at runtime are leaked,
+// but only once per distinct value
+pub fn intern(string: &str) -> &'static str {
+    lazy_static! {
+        static ref STRINGS: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+    }
+    let mut strings = STRINGS.lock().unwrap();
+    match strings.get(string) {
+        Some(&interned) => interned,
+        None => {
+            let interned: &'static str = Box::leak(string.to_owned().into_boxed_str());
+            strings.insert(interned);
+            interned
+        }
+    }
 }
 
 pub trait GuardFn = Fn(&Context) -> bool + Sync + Send + 'static;
@@ -106,6 +484,9 @@ where
 pub enum Action {
     Cancel,
     ToggleHelp,
+    SearchHelp,
+    Palette,
+    Launch,
     Mode(&'static str),
     Call(Arc<Box<dyn ActionFn>>),
     Exec(Arc<Box<dyn ActionFn>>),
@@ -113,8 +494,10 @@ pub enum Action {
 
 #[derive(Clone)]
 pub struct Binding {
-    keystroke: Keystroke,
+    key: KeySpec,
+    keystrokes: Vec<Keystroke>, // resolved from the key by the model
     label: &'static str,
+    description: Option<&'static str>, // longer than the label, possibly several lines
     group: Option<&'static str>,
     guard: Option<Arc<Box<dyn GuardFn>>>,
     action: Action,
@@ -122,29 +505,41 @@ pub struct Binding {
 
 impl Binding {
     pub fn new(
-        keystroke: Keystroke,
+        key: KeySpec,
         label: &'static str,
+        description: Option<&'static str>,
         group: Option<&'static str>,
         guard: Option<Arc<Box<dyn GuardFn>>>,
         action: Action,
     ) -> Binding {
         Self {
-            keystroke,
+            key,
+            keystrokes: Vec::new(),
             label,
+            description,
             group,
             guard,
             action,
         }
     }
 
-    pub fn keystroke(&self) -> Keystroke {
-        self.keystroke
+    pub fn key(&self) -> &KeySpec {
+        &self.key
+    }
+
+    // Empty if the keyboard can't produce the key
+    pub fn keystrokes(&self) -> &[Keystroke] {
+        &self.keystrokes
     }
 
     pub fn label(&self) -> &'static str {
         self.label
     }
 
+    pub fn description(&self) -> Option<&'static str> {
+        self.description
+    }
+
     pub fn group(&self) -> Option<&'static str> {
         self.group
     }
@@ -175,38 +570,72 @@ macro_rules! bindings {
 
 
     (
-        @in_binding $model:ident $mode:tt $group:tt $guard:tt ($($keystrokes:tt)+)
+        @in_binding $model:ident $mode:tt $group:tt $guard:tt ($($key:tt)+) $description:tt
         $label:literal => $new_mode:path
     ) => {
-         $model.add_binding($mode, $($keystrokes)+, $label, $group, $guard, Action::Mode(stringify!($new_mode)))
+         $model.add_binding($mode, $($key)+, $label, $description, $group, $guard, Action::Mode(stringify!($new_mode)))
     };
 
     (
-        @in_binding $model:ident $mode:tt $group:tt $guard:tt  ($($keystrokes:tt)+)
+        @in_binding $model:ident $mode:tt $group:tt $guard:tt ($($key:tt)+) $description:tt
         $label:literal cancel
     ) => {
-         $model.add_binding($mode, $($keystrokes)+, $label, $group, $guard, Action::Cancel)
+         $model.add_binding($mode, $($key)+, $label, $description, $group, $guard, Action::Cancel)
     };
 
     (
-        @in_binding $model:ident $mode:tt $group:tt $guard:tt  ($($keystrokes:tt)+)
+        @in_binding $model:ident $mode:tt $group:tt $guard:tt ($($key:tt)+) $description:tt
         $label:literal toggle help
     ) => {
-         $model.add_binding($mode, $($keystrokes)+, $label, $group, $guard, Action::ToggleHelp)
+         $model.add_binding($mode, $($key)+, $label, $description, $group, $guard, Action::ToggleHelp)
     };
 
     (
-        @in_binding $model:ident $mode:tt $group:tt $guard:tt ($($keystrokes:tt)+)
+        @in_binding $model:ident $mode:tt $group:tt $guard:tt ($($key:tt)+) $description:tt
+        $label:literal search help
+    ) => {
+         $model.add_binding($mode, $($key)+, $label, $description, $group, $guard, Action::SearchHelp)
+    };
+
+    (
+        @in_binding $model:ident $mode:tt $group:tt $guard:tt ($($key:tt)+) $description:tt
+        $label:literal palette
+    ) => {
+         $model.add_binding($mode, $($key)+, $label, $description, $group, $guard, Action::Palette)
+    };
+
+    (
+        @in_binding $model:ident $mode:tt $group:tt $guard:tt ($($key:tt)+) $description:tt
+        $label:literal launch
+    ) => {
+         $model.add_binding($mode, $($key)+, $label, $description, $group, $guard, Action::Launch)
+    };
+
+    (
+        @in_binding $model:ident $mode:tt $group:tt $guard:tt ($($key:tt)+) $description:tt
         $label:literal hydra $($expr:tt)+
     ) => {
-         $model.add_binding($mode, $(keystrokes)+, $label, $group, $guard, Action::Call(bindings!(@new_actionfn $($expr)+)))
+         $model.add_binding($mode, $($key)+, $label, $description, $group, $guard, Action::Call(bindings!(@new_actionfn $($expr)+)))
     };
 
     (
-        @in_binding $model:ident $mode:tt $group:tt $guard:tt ($($keystrokes:tt)+)
+        @in_binding $model:ident $mode:tt $group:tt $guard:tt ($($key:tt)+) $description:tt
         $label:literal $($expr:tt)+
     ) => {
-         $model.add_binding($mode, $($keystrokes)+, $label, $group, $guard, Action::Exec(bindings!(@new_actionfn $($expr)+)))
+         $model.add_binding($mode, $($key)+, $label, $description, $group, $guard, Action::Exec(bindings!(@new_actionfn $($expr)+)))
+    };
+
+
+    // An optional description can follow the label
+    (
+        @in_body $model:ident $mode:tt $group:tt $guard:tt $key:tt
+        $label:literal $description:literal $($rest:tt)+
+    ) => {
+        bindings!(@in_binding $model $mode $group $guard $key (Some($description)) $label $($rest)+)
+    };
+
+    (@in_body $model:ident $mode:tt $group:tt $guard:tt $key:tt $($rest:tt)+) => {
+        bindings!(@in_binding $model $mode $group $guard $key None $($rest)+)
     };
 
 
@@ -236,9 +665,47 @@ macro_rules! bindings {
 
     (
         @in_mode $model:ident $mode:tt $group:tt $guard:tt
-        $head:tt $(+ $tail:tt)* => { $($body:tt)+ } $($rest:tt)*
+        timeout $seconds:literal $($rest:tt)*
     ) => {
-        bindings!(@in_binding $model $mode $group $guard (key!($head $(+ $tail)*)) $($body)+);
+        match $crate::config::duration($seconds as f64)
+            .filter(|&timeout| timeout > std::time::Duration::from_secs(0))
+        {
+            Some(timeout) => $model.set_timeout($mode, timeout),
+            None => log::warn!("Timeout for '{}' must be positive and at most a day", $mode),
+        }
+        bindings!(@in_mode $model $mode $group $guard $($rest)*)
+    };
+
+    (
+        @in_mode $model:ident $mode:tt $group:tt $guard:tt
+        help after $seconds:literal $($rest:tt)*
+    ) => {
+        match $crate::config::duration($seconds as f64) {
+            Some(delay) => $model.set_help_delay($mode, Some(delay)),
+            None => log::warn!("Help delay for '{}' must not be negative or more than a day", $mode),
+        }
+        bindings!(@in_mode $model $mode $group $guard $($rest)*)
+    };
+
+    (
+        @in_mode $model:ident $mode:tt $group:tt $guard:tt
+        help never $($rest:tt)*
+    ) => {
+        $model.set_help_delay($mode, None);
+        bindings!(@in_mode $model $mode $group $guard $($rest)*)
+    };
+
+    // A comma separated sequence of keys, usually just one
+    (
+        @in_mode $model:ident $mode:tt $group:tt $guard:tt
+        $($head:tt $(+ $tail:tt)*),+ => { $($body:tt)+ } $($rest:tt)*
+    ) => {
+        {
+            let mut keys = vec![$(key!($head $(+ $tail)*)),+];
+            let key = keys.pop().unwrap();
+            let mode = $model.add_prefix_modes($mode, $group, keys);
+            bindings!(@in_body $model mode $group $guard (key) $($body)+);
+        }
         bindings!(@in_mode $model $mode $group $guard $($rest)*)
     };
 
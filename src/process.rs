@@ -0,0 +1,22 @@
+use std::{os::unix::process::CommandExt, process::Command};
+
+// Run a shell command in its own session so that it outlives commando and
+// isn't affected by signals sent to commando's process group
+pub fn spawn(command: &str) {
+    log::debug!("Spawn {}", command);
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    unsafe {
+        shell.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    match shell.spawn() {
+        Ok(mut child) => {
+            // Reap the child so it doesn't linger as a zombie
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => log::error!("Failed to run '{}': {}", command, e),
+    }
+}
@@ -0,0 +1,234 @@
+use super::keystroke::{KeySpec, Keystroke};
+use std::sync::Arc;
+use xkbcommon::xkb;
+
+// Number of choices shown at once, which is also how far a page moves
+pub const PROMPT_ROWS: usize = 12;
+
+// A line of text input that fuzzy filters a list of choices. The key dispatcher
+// feeds it keystrokes and the help window draws it.
+#[derive(Clone)]
+pub struct Prompt {
+    title: &'static str,
+    text: String,
+    choices: Arc<Vec<String>>,
+    keys: Arc<Vec<Vec<KeySpec>>>, // the key sequences that the choices are bound to, if any
+    descriptions: Arc<Vec<Option<&'static str>>>, // of the choices, if any
+    matches: Vec<Match>,
+    selected: usize,
+}
+
+#[derive(Clone)]
+pub struct Match {
+    index: usize,
+    score: i32,
+    positions: Vec<usize>,
+}
+
+pub enum PromptResult {
+    Continue,
+    Accept(usize),
+    Cancel,
+}
+
+impl Prompt {
+    pub fn new(title: &'static str, choices: Vec<String>) -> Prompt {
+        let mut prompt = Prompt {
+            title,
+            text: String::new(),
+            choices: Arc::new(choices),
+            keys: Arc::new(Vec::new()),
+            descriptions: Arc::new(Vec::new()),
+            matches: Vec::new(),
+            selected: 0,
+        };
+        prompt.refilter();
+        prompt
+    }
+
+    // Show the keys that each choice is bound to next to it
+    pub fn with_keys(mut self, keys: Vec<Vec<KeySpec>>) -> Prompt {
+        self.keys = Arc::new(keys);
+        self
+    }
+
+    // Show the description of the selected choice below the choices
+    pub fn with_descriptions(mut self, descriptions: Vec<Option<&'static str>>) -> Prompt {
+        self.descriptions = Arc::new(descriptions);
+        self
+    }
+
+    pub fn title(&self) -> &'static str {
+        self.title
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn matches(&self) -> &[Match] {
+        &self.matches
+    }
+
+    pub fn choice(&self, m: &Match) -> &str {
+        &self.choices[m.index]
+    }
+
+    pub fn keys(&self, m: &Match) -> Option<&[KeySpec]> {
+        self.keys.get(m.index).map(|keys| &keys[..])
+    }
+
+    pub fn description(&self, m: &Match) -> Option<&'static str> {
+        self.descriptions.get(m.index).copied().flatten()
+    }
+
+    pub fn descriptions<'a>(&'a self) -> impl Iterator<Item = &'static str> + 'a {
+        self.descriptions
+            .iter()
+            .filter_map(|&description| description)
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn handle_keystroke(&mut self, keystroke: Keystroke) -> PromptResult {
+        let control = keystroke.modifiers() & xcb::KEY_BUT_MASK_CONTROL as u16 != 0;
+        let keysym = keystroke.keysym();
+        match (control, keysym) {
+            (_, xkb::KEY_Escape) | (true, xkb::KEY_g) => return PromptResult::Cancel,
+            (_, xkb::KEY_Return) | (_, xkb::KEY_KP_Enter) => {
+                return match self.matches.get(self.selected) {
+                    Some(m) => PromptResult::Accept(m.index),
+                    None => PromptResult::Cancel,
+                };
+            }
+            (_, xkb::KEY_Up) | (true, xkb::KEY_p) | (_, xkb::KEY_ISO_Left_Tab) => {
+                self.selected = self.selected.saturating_sub(1);
+            }
+            (_, xkb::KEY_Down) | (true, xkb::KEY_n) | (_, xkb::KEY_Tab) => {
+                if self.selected + 1 < self.matches.len() {
+                    self.selected += 1;
+                }
+            }
+            (_, xkb::KEY_Prior) => {
+                self.selected = self.selected.saturating_sub(PROMPT_ROWS);
+            }
+            (_, xkb::KEY_Next) => {
+                self.selected =
+                    (self.selected + PROMPT_ROWS).min(self.matches.len().saturating_sub(1));
+            }
+            (_, xkb::KEY_BackSpace) => {
+                self.text.pop();
+                self.refilter();
+            }
+            (true, xkb::KEY_u) => {
+                self.text.clear();
+                self.refilter();
+            }
+            (false, _) => {
+                let text = xkb::keysym_to_utf8(keysym);
+                if !text.is_empty() && text.chars().all(|c| !c.is_control()) {
+                    self.text.push_str(&text);
+                    self.refilter();
+                }
+            }
+            _ => {}
+        }
+        PromptResult::Continue
+    }
+
+    fn refilter(&mut self) {
+        let text = &self.text;
+        self.matches = self
+            .choices
+            .iter()
+            .enumerate()
+            .filter_map(|(index, choice)| {
+                fuzzy_match(text, choice).map(|(score, positions)| Match {
+                    index,
+                    score,
+                    positions,
+                })
+            })
+            .collect();
+        // Stable, so equal scores keep the order of the choices
+        self.matches.sort_by_key(|m| -m.score);
+        self.selected = 0;
+    }
+}
+
+impl Match {
+    // Char (not byte) indices of the matched characters
+    pub fn positions(&self) -> &[usize] {
+        &self.positions
+    }
+}
+
+// Match the characters of the pattern in order, case insensitively. Consecutive
+// characters and characters at the start of words score higher, as do shorter
+// candidates.
+pub fn fuzzy_match(pattern: &str, candidate: &str) -> Option<(i32, Vec<usize>)> {
+    let mut pattern = pattern.chars().flat_map(char::to_lowercase).peekable();
+    let mut positions = Vec::new();
+    let mut score = 0;
+    let mut previous: Option<char> = None;
+    let mut last_match: Option<usize> = None;
+
+    for (index, c) in candidate.chars().enumerate() {
+        match pattern.peek() {
+            Some(&p) => {
+                if c.to_lowercase().eq(std::iter::once(p)) {
+                    score += 1;
+                    if last_match.map_or(index == 0, |last| last + 1 == index) {
+                        score += 5;
+                    }
+                    if previous.map_or(true, |previous| !previous.is_alphanumeric())
+                        || (c.is_uppercase() && previous.map_or(false, char::is_lowercase))
+                    {
+                        score += 3;
+                    }
+                    positions.push(index);
+                    last_match = Some(index);
+                    pattern.next();
+                }
+            }
+            None => break,
+        }
+        previous = Some(c);
+    }
+
+    if pattern.peek().is_some() {
+        None
+    } else {
+        Some((score * 100 - candidate.chars().count() as i32, positions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_match_finds_characters_in_order() {
+        assert_eq!(fuzzy_match("ff", "Firefox"), Some((993, vec![0, 4])));
+        assert_eq!(fuzzy_match("FIRE", "firefox").unwrap().1, vec![0, 1, 2, 3]);
+        assert_eq!(fuzzy_match("", "Firefox"), Some((-7, vec![])));
+        assert_eq!(fuzzy_match("xf", "Firefox"), None);
+        assert_eq!(fuzzy_match("chrome", "Firefox"), None);
+    }
+
+    #[test]
+    fn fuzzy_match_prefers_word_starts_and_runs() {
+        let score = |pattern, candidate| fuzzy_match(pattern, candidate).unwrap().0;
+        assert!(score("gc", "git commit") > score("gc", "logcat"));
+        assert!(score("fb", "fooBar") > score("fb", "foobar"));
+        assert!(score("fire", "Firefox") > score("fire", "Fixed tire"));
+    }
+
+    #[test]
+    fn fuzzy_match_prefers_shorter_candidates() {
+        let score = |candidate| fuzzy_match("fire", candidate).unwrap().0;
+        assert!(score("Firefox") > score("Firefox ESR"));
+    }
+}
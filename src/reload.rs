@@ -0,0 +1,126 @@
+use super::{config, connection, model::Model};
+use crossbeam::channel::{Receiver, Sender};
+use std::{path::PathBuf, time::Duration};
+
+pub const RELOAD_MESSAGE: &str = "COMMANDO_RELOAD";
+
+// Sent along with a trigger by someone waiting for the outcome of the reload
+pub type Reply = Sender<Result<(), config::Error>>;
+
+// Rebuild the model on SIGHUP, when the configuration file changes, or when triggered
+// through the returned sender. New models are sent on the returned receiver and the
+// key dispatcher is woken with a RELOAD_MESSAGE so that it can swap them in without
+// losing its modal state.
+pub fn spawn<F>(path: Option<PathBuf>, create_model: F) -> (Sender<Option<Reply>>, Receiver<Model>)
+where
+    F: Fn() -> Result<Model, config::Error> + Send + 'static,
+{
+    let message_type = connection::intern_atom(RELOAD_MESSAGE);
+    connection::message_window();
+
+    let (trigger_tx, trigger_rx) = crossbeam::channel::unbounded();
+    let (model_tx, model_rx) = crossbeam::channel::unbounded();
+
+    watch_signals(trigger_tx.clone());
+    if let Some(path) = path {
+        watch_file(path, trigger_tx.clone());
+    }
+
+    std::thread::spawn(move || {
+        while let Ok(mut reply) = trigger_rx.recv() {
+            // Editors often save in several steps, so wait for things to settle unless
+            // someone is waiting for the result
+            while reply.is_none() {
+                match trigger_rx.recv_timeout(Duration::from_millis(200)) {
+                    Ok(trigger) => reply = trigger,
+                    Err(_) => break,
+                }
+            }
+            let result = match create_model() {
+                Ok(model) => {
+                    if model_tx.send(model).is_err() {
+                        break;
+                    }
+                    connection::send_message(message_type, 0);
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            };
+            match reply {
+                Some(reply) => {
+                    let _ = reply.send(result);
+                }
+                None => {
+                    if let Err(e) = result {
+                        log::error!("Failed to reload bindings: {}", e);
+                    }
+                }
+            }
+        }
+    });
+
+    (trigger_tx, model_rx)
+}
+
+fn watch_signals(trigger_tx: Sender<Option<Reply>>) {
+    match signal_hook::iterator::Signals::new(&[signal_hook::SIGHUP]) {
+        Ok(signals) => {
+            std::thread::spawn(move || {
+                for _ in signals.forever() {
+                    log::info!("Got SIGHUP, reloading bindings");
+                    if trigger_tx.send(None).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        Err(e) => log::error!("Failed to install SIGHUP handler: {}", e),
+    }
+}
+
+fn watch_file(path: PathBuf, trigger_tx: Sender<Option<Reply>>) {
+    let (directory, file_name) = match (path.parent(), path.file_name()) {
+        (Some(directory), Some(file_name)) => (directory.to_owned(), file_name.to_owned()),
+        _ => return,
+    };
+
+    // Watch the directory rather than the file because editors commonly replace
+    // the file, and the file may not exist yet
+    let mut inotify = match inotify::Inotify::init() {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            log::error!("Failed to initialise inotify: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = inotify.add_watch(
+        &directory,
+        inotify::WatchMask::CLOSE_WRITE | inotify::WatchMask::MOVED_TO | inotify::WatchMask::DELETE,
+    ) {
+        log::warn!("Not watching {} for changes: {}", directory.display(), e);
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => {
+                    if events
+                        .into_iter()
+                        .any(|e| e.name == Some(file_name.as_os_str()))
+                    {
+                        log::info!("{} changed, reloading bindings", path.display());
+                        if trigger_tx.send(None).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to read inotify events: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,286 @@
+use super::connection;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+// The look of the help window, from the [help.theme] table of the configuration
+// file. Anything it leaves out comes from commando.* X resources, e.g.
+//
+//   commando.font: Noto Sans 12px
+//   commando.headerBackground: #e6ffe6
+//
+// and otherwise from the defaults.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub font: String,
+    pub key_font: String,
+    pub symbol_font: String,
+
+    pub foreground: Color,
+    pub background: Color,
+    pub opacity: f64,  // of the backgrounds, when the window is translucent
+    pub accent: Color, // titles, hydra heads and matches in prompts
+    pub header_background: Color,
+    pub separator: Color, // under headers, and behind the selection in prompts
+    pub group_foreground: Color,
+    pub group_rule: Color,
+    pub arrow: Color,
+
+    pub padding: u32,       // around the edges and between columns
+    pub group_spacing: u32, // above group headers
+    pub border_width: u32,
+    pub border_color: Color,
+    pub corner_radius: f64,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            font: "Noto Sans 11px".to_owned(),
+            key_font: "Noto Sans Mono 11px".to_owned(),
+            symbol_font: "Lucida Grande 11px".to_owned(),
+
+            foreground: Color(0.0, 0.0, 0.0),
+            background: Color(1.0, 1.0, 0.95),
+            opacity: 1.0,
+            accent: Color(0.0, 0.5, 0.0),
+            header_background: Color(0.9, 1.0, 0.9),
+            separator: Color(0.8, 0.9, 0.8),
+            group_foreground: Color(0.0, 0.5, 0.0),
+            group_rule: Color(0.7, 0.85, 0.7),
+            arrow: Color(0.7, 0.7, 0.7),
+
+            padding: 10,
+            group_spacing: 8,
+            border_width: 1,
+            border_color: Color(0.0, 0.0, 0.0),
+            corner_radius: 0.0,
+        }
+    }
+}
+
+impl Theme {
+    // The defaults, overridden by resources that have been loaded with xrdb
+    pub fn from_resources() -> Self {
+        let mut theme = Self::default();
+
+        for (name, value) in connection::resources() {
+            let name = match name.find('.') {
+                Some(index) if name[..index] == *"commando" || name[..index] == *"Commando" => {
+                    &name[index + 1..]
+                }
+                _ => continue,
+            };
+            if let Err(e) = theme.set_resource(name, &value) {
+                log::warn!("Ignoring resource commando.{}: {}", name, e);
+            }
+        }
+
+        theme
+    }
+
+    // Resource names are the field names in camel case, as is usual for X
+    fn set_resource(&mut self, name: &str, value: &str) -> Result<(), String> {
+        fn number<T: FromStr>(value: &str) -> Result<T, String> {
+            value
+                .parse()
+                .map_err(|_| format!("'{}' is not a number", value))
+        }
+
+        match name {
+            "font" => self.font = value.to_owned(),
+            "keyFont" => self.key_font = value.to_owned(),
+            "symbolFont" => self.symbol_font = value.to_owned(),
+
+            "foreground" => self.foreground = value.parse()?,
+            "background" => self.background = value.parse()?,
+            "opacity" => self.opacity = number(value)?,
+            "accent" => self.accent = value.parse()?,
+            "headerBackground" => self.header_background = value.parse()?,
+            "separator" => self.separator = value.parse()?,
+            "groupForeground" => self.group_foreground = value.parse()?,
+            "groupRule" => self.group_rule = value.parse()?,
+            "arrow" => self.arrow = value.parse()?,
+
+            "padding" => self.padding = number(value)?,
+            "groupSpacing" => self.group_spacing = number(value)?,
+            "borderWidth" => self.border_width = number(value)?,
+            "borderColor" => self.border_color = value.parse()?,
+            "cornerRadius" => self.corner_radius = number(value)?,
+
+            _ => return Err("no such setting".to_owned()),
+        }
+        Ok(())
+    }
+}
+
+// The [help.theme] table as written, which is only applied over the resources
+// on the help thread, so that loading the configuration doesn't talk to X
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct ThemeSettings(BTreeMap<String, toml::Value>);
+
+impl ThemeSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        self.apply(&mut Theme::default())
+    }
+
+    pub fn apply(&self, theme: &mut Theme) -> Result<(), String> {
+        for (name, value) in &self.0 {
+            let value = match value {
+                toml::Value::String(string) => string.clone(),
+                toml::Value::Integer(integer) => integer.to_string(),
+                toml::Value::Float(float) => float.to_string(),
+                _ => return Err(format!("Theme {} must be a string or a number", name)),
+            };
+            if name.chars().any(char::is_uppercase) {
+                return Err(format!("Theme {}: no such setting", name));
+            }
+            theme
+                .set_resource(&camel_case(name), &value)
+                .map_err(|e| format!("Theme {}: {}", name, e))?;
+        }
+        Ok(())
+    }
+}
+
+// The resource name for a field, e.g. keyFont for key_font
+fn camel_case(name: &str) -> String {
+    let mut words = name.split('_');
+    let first = words.next().unwrap_or_default().to_owned();
+    words.fold(first, |mut camel, word| {
+        let mut chars = word.chars();
+        if let Some(c) = chars.next() {
+            camel.extend(c.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+        camel
+    })
+}
+
+// Red, green and blue from 0 to 1, written as "#rrggbb" or "#rgb"
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Color(pub f64, pub f64, pub f64);
+
+impl Color {
+    pub fn set_source(&self, cairo_context: &cairo::Context) {
+        cairo_context.set_source_rgb(self.0, self.1, self.2);
+    }
+
+    pub fn set_source_with_alpha(&self, cairo_context: &cairo::Context, alpha: f64) {
+        cairo_context.set_source_rgba(self.0, self.1, self.2, alpha);
+    }
+
+    // For Pango markup
+    pub fn to_hex(&self) -> String {
+        let byte = |c: f64| (c * 255.0).round() as u8;
+        format!(
+            "#{:02x}{:02x}{:02x}",
+            byte(self.0),
+            byte(self.1),
+            byte(self.2)
+        )
+    }
+
+    // The pixel value for the root window's visual, which is TrueColor but may
+    // have any depth, e.g. 16 bit
+    pub fn pixel(&self) -> u32 {
+        self.pixel_for(connection::root_visual_masks())
+    }
+
+    fn pixel_for(&self, (red, green, blue): (u32, u32, u32)) -> u32 {
+        let component = |c: f64, mask: u32| match mask {
+            0 => 0,
+            _ => {
+                let shift = mask.trailing_zeros();
+                (((c * (mask >> shift) as f64).round() as u32) << shift) & mask
+            }
+        };
+        component(self.0, red) | component(self.1, green) | component(self.2, blue)
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{}' is not a color like #rrggbb", string);
+        let digits = match string.trim() {
+            s if s.starts_with('#') && s.is_ascii() => &s[1..],
+            _ => return Err(invalid()),
+        };
+        let width = match digits.len() {
+            3 => 1,
+            6 => 2,
+            _ => return Err(invalid()),
+        };
+        let component = |index: usize| -> Result<f64, String> {
+            let value = u8::from_str_radix(&digits[index * width..(index + 1) * width], 16)
+                .map_err(|_| invalid())?;
+            // "#f00" is the same as "#ff0000"
+            let max = if width == 1 { 15.0 } else { 255.0 };
+            Ok(value as f64 / max)
+        };
+        Ok(Color(component(0)?, component(1)?, component(2)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_parses_long_and_short_forms() {
+        assert_eq!("#ff0000".parse(), Ok(Color(1.0, 0.0, 0.0)));
+        assert_eq!("#f00".parse(), Ok(Color(1.0, 0.0, 0.0)));
+        assert_eq!(" #000000 ".parse(), Ok(Color(0.0, 0.0, 0.0)));
+        assert_eq!("#336699".parse(), "#369".parse::<Color>());
+        assert_eq!("#ABCDEF".parse::<Color>().unwrap().to_hex(), "#abcdef");
+    }
+
+    #[test]
+    fn color_rejects_other_forms() {
+        for string in &["", "#", "ff0000", "#ff00", "#ff00000", "#gg0000", "#ff00é"] {
+            assert!(string.parse::<Color>().is_err(), "{}", string);
+        }
+    }
+
+    #[test]
+    fn color_pixel_follows_the_visual_masks() {
+        let color = Color(1.0, 0.5, 0.0);
+        assert_eq!(color.pixel_for((0xff_0000, 0xff00, 0xff)), 0xff_8000);
+        // 16 bit, with 5 bits of red and blue and 6 of green
+        assert_eq!(color.pixel_for((0xf800, 0x07e0, 0x001f)), 0xfc00);
+        assert_eq!(
+            Color(0.0, 0.0, 1.0).pixel_for((0xff, 0xff00, 0xff_0000)),
+            0xff_0000
+        );
+        assert_eq!(color.pixel_for((0, 0, 0)), 0);
+    }
+
+    #[test]
+    fn theme_settings_apply_over_a_theme() {
+        let settings: ThemeSettings =
+            toml::from_str("key_font = 'Mono 9px'\npadding = 4\nheader_background = '#fff'")
+                .unwrap();
+        let mut theme = Theme::default();
+        assert_eq!(settings.apply(&mut theme), Ok(()));
+        assert_eq!(theme.key_font, "Mono 9px");
+        assert_eq!(theme.padding, 4);
+        assert_eq!(theme.header_background, Color(1.0, 1.0, 1.0));
+        assert_eq!(theme.font, Theme::default().font);
+    }
+
+    #[test]
+    fn theme_settings_are_validated() {
+        for string in &[
+            "keyFont = 'Mono'",
+            "fonts = 'Mono'",
+            "accent = 'green'",
+            "padding = true",
+        ] {
+            let settings: ThemeSettings = toml::from_str(string).unwrap();
+            assert!(settings.validate().is_err(), "{}", string);
+        }
+    }
+}
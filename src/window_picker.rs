@@ -0,0 +1,300 @@
+use super::{connection, help, keystroke::Keystroke, theme::Theme};
+use lazy_static::lazy_static;
+use pango::LayoutExt;
+use std::sync::RwLock;
+use xkbcommon::xkb;
+
+// Home row keys, used for hint labels
+const LABEL_CHARACTERS: &[char] = &['a', 's', 'd', 'f', 'g', 'h', 'j', 'k', 'l'];
+
+// Picking a window is cancelled with the same keys as a mode, and the hints are
+// drawn in the help window's theme. Both depend on the model, so the key dispatcher
+// keeps them up to date.
+lazy_static! {
+    static ref SETTINGS: RwLock<(Vec<Keystroke>, help::Settings)> = Default::default();
+}
+
+pub fn configure(cancel_keys: Vec<Keystroke>, help_settings: help::Settings) {
+    *SETTINGS.write().unwrap() = (cancel_keys, help_settings);
+}
+
+struct Hint {
+    window: xcb::Window,
+    overlay: xcb::Window,
+    label: String,
+}
+
+// Replace {focused_window} and {selected_window} in a command with window ids. The
+// selected window is chosen interactively, and None is returned if that's cancelled.
+pub fn expand_placeholders(command: &str) -> Option<String> {
+    let mut command = command.to_owned();
+    if command.contains("{focused_window}") {
+        let window = connection::focused_window()?;
+        command = command.replace("{focused_window}", &window.to_string());
+    }
+    if command.contains("{selected_window}") {
+        let window = pick_window()?;
+        command = command.replace("{selected_window}", &window.to_string());
+    }
+    Some(command)
+}
+
+// Label every visible window and wait for one of the labels to be typed
+pub fn pick_window() -> Option<xcb::Window> {
+    let windows = visible_windows();
+    if windows.is_empty() {
+        return None;
+    }
+
+    let (cancel_keys, theme) = {
+        let settings = SETTINGS.read().unwrap();
+        (
+            settings.0.clone(),
+            settings.1.theme(Theme::from_resources()),
+        )
+    };
+    let font = hint_font(&theme);
+
+    let labels = make_labels(windows.len());
+    let hints: Vec<Hint> = windows
+        .into_iter()
+        .zip(labels)
+        .map(|(window, label)| create_hint(window, label, &font, &theme))
+        .collect();
+    connection::connection().flush();
+
+    // A mode may already have grabbed the keyboard, and keeps its grab afterwards
+    let was_grabbed = connection::keyboard_is_grabbed();
+    if !was_grabbed {
+        connection::grab_keyboard();
+    }
+
+    // Other events are left for the key dispatcher, so that e.g. a reload isn't lost
+    let mut other_events = Vec::new();
+    let mut typed = String::new();
+    let result = loop {
+        let event = match connection::wait_for_event() {
+            Some(event) => event,
+            None => break None,
+        };
+        match event.response_type() & !0x80 {
+            xcb::KEY_PRESS => {
+                let press_event: &xcb::KeyPressEvent = unsafe { xcb::cast_event(&event) };
+                let key = Keystroke::from(press_event);
+                if key.is_modifier() {
+                    continue;
+                }
+                if cancel_keys.contains(&key) {
+                    break None;
+                }
+
+                typed.push_str(&xkb::keysym_to_utf8(key.keysym()));
+                let candidates: Vec<&Hint> = hints
+                    .iter()
+                    .filter(|hint| hint.label.starts_with(&typed))
+                    .collect();
+                match candidates.as_slice() {
+                    [] => break None,
+                    [hint] if hint.label == typed => break Some(hint.window),
+                    _ => {
+                        for hint in &hints {
+                            draw_hint(hint, &typed, &font, &theme);
+                        }
+                    }
+                }
+            }
+
+            xcb::EXPOSE => {
+                let expose_event: &xcb::ExposeEvent = unsafe { xcb::cast_event(&event) };
+                match hints.iter().find(|h| h.overlay == expose_event.window()) {
+                    Some(hint) => draw_hint(hint, &typed, &font, &theme),
+                    None => other_events.push(event),
+                }
+            }
+
+            xcb::KEY_RELEASE => {}
+
+            _ => other_events.push(event),
+        }
+    };
+    for event in other_events {
+        connection::defer_event(event);
+    }
+
+    if !was_grabbed {
+        connection::ungrab_keyboard();
+    }
+    for hint in &hints {
+        xcb::destroy_window(connection::connection(), hint.overlay);
+    }
+    connection::connection().flush();
+
+    log::debug!("Picked window {:?}", result);
+    result
+}
+
+// As few characters as it takes for every window to have a label, all the same
+// length so that none is the start of another
+fn make_labels(count: usize) -> Vec<String> {
+    let mut labels: Vec<String> = LABEL_CHARACTERS.iter().map(|c| c.to_string()).collect();
+    while labels.len() < count {
+        labels = labels
+            .iter()
+            .flat_map(|label| {
+                LABEL_CHARACTERS
+                    .iter()
+                    .map(move |c| format!("{}{}", label, c))
+            })
+            .collect();
+    }
+    labels.truncate(count);
+    labels
+}
+
+// Managed windows that are mapped, with their position relative to the root
+fn visible_windows() -> Vec<(xcb::Window, i16, i16, u16, u16)> {
+    let connection = connection::connection();
+    let root = connection.get_setup().roots().nth(0).unwrap().root();
+
+    let windows: Vec<xcb::Window> = match connection::get_property(
+        root,
+        connection::intern_atom("_NET_CLIENT_LIST"),
+        xcb::ATOM_WINDOW,
+    ) {
+        Some(reply) => reply.value::<u32>().to_vec(),
+        None => match xcb::query_tree(connection, root).get_reply() {
+            Ok(reply) => reply.children().to_vec(),
+            Err(_) => Vec::new(),
+        },
+    };
+
+    windows
+        .into_iter()
+        .filter(
+            |&window| match xcb::get_window_attributes(connection, window).get_reply() {
+                Ok(attributes) => {
+                    attributes.map_state() == xcb::MAP_STATE_VIEWABLE as u8
+                        && !attributes.override_redirect()
+                        && attributes.class() == xcb::WINDOW_CLASS_INPUT_OUTPUT as u16
+                }
+                Err(_) => false,
+            },
+        )
+        .filter_map(|window| {
+            let geometry = xcb::get_geometry(connection, window).get_reply().ok()?;
+            let position = xcb::translate_coordinates(connection, window, root, 0, 0)
+                .get_reply()
+                .ok()?;
+            Some((
+                window,
+                position.dst_x(),
+                position.dst_y(),
+                geometry.width(),
+                geometry.height(),
+            ))
+        })
+        .collect()
+}
+
+// The key font, large enough to read from across the screen
+fn hint_font(theme: &Theme) -> pango::FontDescription {
+    let mut font = pango::FontDescription::from_string(&theme.key_font);
+    if font.get_size_is_absolute() {
+        font.set_absolute_size(2.0 * font.get_size() as f64);
+    } else {
+        font.set_size(2 * font.get_size());
+    }
+    font.set_weight(pango::Weight::Bold);
+    font
+}
+
+// The label's size with some padding, measured before there's a window to draw in
+fn hint_size(label: &str, font: &pango::FontDescription) -> (u16, u16) {
+    let (width, height) = cairo::ImageSurface::create(cairo::Format::ARgb32, 1, 1)
+        .ok()
+        .and_then(|surface| {
+            let cairo_context = cairo::Context::new(&surface);
+            let layout = pangocairo::functions::create_layout(&cairo_context)?;
+            layout.set_font_description(font);
+            layout.set_text(label);
+            Some(layout.get_pixel_size())
+        })
+        .unwrap_or((14 * label.len() as i32, 24));
+    (width as u16 + 16, height as u16 + 8)
+}
+
+fn create_hint(
+    (window, x, y, width, height): (xcb::Window, i16, i16, u16, u16),
+    label: String,
+    font: &pango::FontDescription,
+    theme: &Theme,
+) -> Hint {
+    let connection = connection::connection();
+    let screen = connection.get_setup().roots().nth(0).unwrap();
+
+    let (hint_width, hint_height) = hint_size(&label, font);
+
+    let overlay = connection.generate_id();
+    xcb::create_window(
+        connection,
+        xcb::COPY_FROM_PARENT as u8,
+        overlay,
+        screen.root(),
+        x + (width as i16 - hint_width as i16) / 2,
+        y + (height as i16 - hint_height as i16) / 2,
+        hint_width,
+        hint_height,
+        theme.border_width as u16,
+        xcb::WINDOW_CLASS_INPUT_OUTPUT as u16,
+        screen.root_visual(),
+        &[
+            (xcb::CW_BACK_PIXEL, theme.background.pixel()),
+            (xcb::CW_BORDER_PIXEL, theme.border_color.pixel()),
+            (xcb::CW_OVERRIDE_REDIRECT, 1),
+            (xcb::CW_EVENT_MASK, xcb::EVENT_MASK_EXPOSURE),
+        ],
+    );
+    xcb::map_window(connection, overlay);
+
+    Hint {
+        window,
+        overlay,
+        label,
+    }
+}
+
+// The part of the label that has already been typed is dimmed
+fn draw_hint(hint: &Hint, typed: &str, font: &pango::FontDescription, theme: &Theme) {
+    if let Ok(surface) = connection::get_cairo_surface(hint.overlay) {
+        let cairo_context = cairo::Context::new(&surface);
+        if let Some(layout) = pangocairo::functions::create_layout(&cairo_context) {
+            layout.set_font_description(font);
+
+            theme.background.set_source(&cairo_context);
+            cairo_context.paint();
+
+            let typed_length = if hint.label.starts_with(typed) {
+                typed.len()
+            } else {
+                0
+            };
+            let (done, remaining) = hint.label.split_at(typed_length);
+            layout.set_markup(&format!(
+                "<span foreground=\"{}\">{}</span>{}",
+                theme.arrow.to_hex(),
+                done,
+                remaining
+            ));
+
+            let (width, height) = layout.get_pixel_size();
+            let (hint_width, hint_height) = hint_size(&hint.label, font);
+            theme.foreground.set_source(&cairo_context);
+            cairo_context.move_to(
+                ((hint_width as i32 - width) / 2) as f64,
+                ((hint_height as i32 - height) / 2) as f64,
+            );
+            pangocairo::functions::show_layout(&cairo_context, &layout);
+        }
+    }
+    connection::connection().flush();
+}